@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The crate is a binary, so the parser is pulled in directly. It only depends on `serde_json`.
+#[path = "../../src/debug_json.rs"]
+mod debug_json;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        // Whatever the input, parsing mustn't panic, and anything it accepts has to be JSON that
+        // reads back as the same value.
+        if let Ok(value) = debug_json::parse(source) {
+            let json = value.to_string();
+            let reparsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value, reparsed);
+        }
+    }
+});
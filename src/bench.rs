@@ -0,0 +1,83 @@
+//! A quick-and-dirty benchmark of `CustomJsonLayer`.
+//!
+//! Run it with `cargo run --release -- bench`. It logs the same events with a couple of different
+//! layer configurations, throws the output away, and prints how long each configuration took.
+
+use std::time::{Duration, Instant};
+
+use tracing::{info, info_span};
+use tracing_subscriber::prelude::*;
+
+use crate::custom_layer::{CustomJsonLayer, EventData, Processed};
+
+const ITERATIONS: usize = 200_000;
+
+pub fn run() {
+    compare(
+        "callsite metadata",
+        [
+            (
+                "serialized per event",
+                CustomJsonLayer::default().with_precomputed_metadata(false),
+            ),
+            (
+                "precomputed per callsite",
+                CustomJsonLayer::default().with_precomputed_metadata(true),
+            ),
+        ],
+    );
+    compare(
+        "event data",
+        [
+            ("borrowed from the spans", CustomJsonLayer::default()),
+            (
+                "copied for a processor",
+                CustomJsonLayer::default().with_processor(|_: &mut EventData| Processed::Keep),
+            ),
+        ],
+    );
+}
+
+/// Time each of the layers against each other.
+fn compare<const N: usize>(name: &str, layers: [(&str, CustomJsonLayer); N]) {
+    println!("{}", name);
+    for (label, layer) in layers {
+        let subscriber = tracing_subscriber::registry().with(layer.with_writer(std::io::sink));
+        let elapsed = tracing::subscriber::with_default(subscriber, || {
+            // Warm up first, so that every callsite is registered and the allocator is primed.
+            log_some_things(ITERATIONS / 10);
+            let start = Instant::now();
+            log_some_things(ITERATIONS);
+            start.elapsed()
+        });
+        println!(
+            "  {:<30} {:>10.2?} total, {:>8.0} ns/event",
+            label,
+            elapsed,
+            per_event(elapsed)
+        );
+    }
+}
+
+fn log_some_things(iterations: usize) {
+    let outer = info_span!("request", method = "GET", path = "/api/v1/models");
+    let _outer = outer.enter();
+    let inner = info_span!(
+        target: "tracing_valuable_test::bench::a::deeply::nested::module",
+        "load_model",
+        model_id = 42
+    );
+    let _inner = inner.enter();
+
+    for i in 0..iterations {
+        info!(
+            target: "tracing_valuable_test::bench::a::deeply::nested::module",
+            message = "loaded model",
+            iteration = i,
+        );
+    }
+}
+
+fn per_event(elapsed: Duration) -> f64 {
+    elapsed.as_nanos() as f64 / ITERATIONS as f64
+}
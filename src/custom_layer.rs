@@ -8,27 +8,109 @@
 //! ```no_compile
 //! impl<'a> Visit for JsonAttributeVisitor<'a> {
 //!     fn record_value(&mut self, field: &tracing::field::Field, value: valuable::Value<'_>) {
-//!         self.data_mut().insert(
-//!             field.name(),
-//!             json!(valuable_serde::Serializable::new(value)),
-//!         );
+//!         let numbers = self.data_mut().options.numbers;
+//!         let value = ToJson::new(&numbers).convert(value);
+//!         self.data_mut().insert(field.name(), value);
 //!     }
 //! }
 //! ```
+//!
+//! `ToJson` writes the same shapes as `valuable_serde` would, but applies the layer's numeric
+//! policy on the way.
+
+mod bytes;
+mod callsite;
+mod canonical;
+mod cardinality;
+mod coalesce;
+mod dotted;
+mod enrichment;
+mod filter;
+mod keys;
+mod message;
+mod numeric;
+mod panic;
+mod pipeline;
+mod promote;
+mod recorder;
+mod sampling;
+mod stability;
+mod tail;
+#[cfg(test)]
+pub(crate) mod testing;
+mod to_json;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use indexmap::IndexMap;
-use serde::{
-    ser::{SerializeMap, SerializeSeq},
-    Serializer,
-};
+use serde::{ser::SerializeMap, Serializer};
 use serde_json::json;
-use std::{cell::Cell, io::Write};
-use tracing::{field::Visit, span, Level, Metadata, Subscriber};
-use tracing_subscriber::{registry::Scope, Layer};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    io::Write,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{field::Visit, span, subscriber::Interest, Level, Metadata, Subscriber};
+use tracing_subscriber::{fmt::MakeWriter, Layer};
+
+pub use bytes::BytesEncoding;
+use callsite::{CallsiteCache, CallsiteFragments};
+use canonical::CanonicalLine;
+pub use canonical::CanonicalLines;
+pub use cardinality::CardinalityFallback;
+use cardinality::CardinalityGuard;
+use coalesce::{Coalesced, Coalescer};
+use enrichment::Enrichment;
+use keys::KeyTransform;
+pub use keys::{KeyCase, KeyDepth};
+pub use numeric::{NonFiniteFloats, MAX_SAFE_INTEGER};
+use numeric::{NumericPolicy, Stringified};
+pub use panic::PanicHook;
+use pipeline::Pipeline;
+pub use promote::Promotion;
+pub use recorder::{FlightRecorder, RecorderScope};
+pub use sampling::HeadSampling;
+use sampling::Sampled;
+use stability::TypeStability;
+pub use stability::{TypeMismatch, TypeScope};
+use tail::TailBuffer;
+pub use tail::TailSampling;
+use to_json::ToJson;
+
+use crate::debug_json;
+use crate::filter_expr::Filter;
+pub use pipeline::{EventData, Fields, Processed, Processor, SpanData};
 
 pub const SPECIAL_JSON_PREFIX: &str = "!custom_layer_tracing_json!";
 
+/// The top-level keys the layer writes itself, some only when a feature that adds them is turned
+/// on. Enrichment keys and promotions can't use them, or a line would have the same key twice.
+const RESERVED_KEYS: &[&str] = &[
+    "timestamp",
+    "level",
+    "message",
+    "message_template",
+    "target",
+    "fields",
+    "context",
+    "span",
+    "root_span",
+    "spans",
+    "span_ids",
+    "warnings",
+    "field_conflicts",
+    "backfilled",
+    "canonical",
+    "duration_ms",
+    "event_counts",
+    "last_error",
+    "counters",
+    "repeat_count",
+    "first_timestamp",
+    "last_timestamp",
+];
+
 /// A `tracing_subscriber::Layer` that outputs trace data in a format that we like.
 ///
 /// ```
@@ -38,19 +120,1122 @@ pub const SPECIAL_JSON_PREFIX: &str = "!custom_layer_tracing_json!";
 /// let layer = CustomJsonLayer::default();
 /// tracing_subscriber::registry().with(layer).init();
 /// ```
-pub struct CustomJsonLayer;
+pub struct CustomJsonLayer<W = fn() -> std::io::Stdout>
+where
+    W: for<'w> MakeWriter<'w>,
+{
+    make_writer: W,
+    precompute_metadata: bool,
+    span_layout: SpanLayout,
+    /// How field values are recorded.
+    record: RecordOptions,
+    span_context: SpanContext,
+    uninherited_fields: HashSet<String>,
+    unset_fields: UnsetFields,
+    top_level_message: bool,
+    message_templates: bool,
+    /// Indexed by `level_index`.
+    span_output: [SpanOutput; 5],
+    enrichment: Enrichment,
+    /// The keys of enrichment fields and promotions, as configured, to check that they're written
+    /// under names that don't clash.
+    top_level_keys: Vec<String>,
+    pipeline: Pipeline,
+    expand_dotted_fields: bool,
+    type_stability: Option<TypeStability>,
+    cardinality: Option<CardinalityGuard>,
+    keys: KeyTransform,
+    flight_recorder: Option<FlightRecorder>,
+    tail_sampling: Option<TailSampling>,
+    canonical_lines: Option<CanonicalLines>,
+    head_sampling: Option<HeadSampling>,
+    coalescer: Option<Coalescer>,
+    callsites: CallsiteCache,
+}
+
+/// How a span's metadata (its `target` and `name`) is laid out next to the fields recorded on it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanLayout {
+    /// Metadata and fields share one object.
+    ///
+    /// ```json
+    /// { "target": "...", "name": "load", "user_id": 7 }
+    /// ```
+    ///
+    /// Fields that would collide with the metadata are written with a `fields.` prefix instead, so
+    /// `info_span!("load", name = %user)` becomes `{ "name": "load", "fields.name": "..." }`.
+    #[default]
+    Flat,
+    /// Fields get their own object.
+    ///
+    /// ```json
+    /// { "name": "load", "target": "...", "fields": { "name": "...", "user_id": 7 } }
+    /// ```
+    Nested,
+}
+
+/// What to do when a field is recorded more than once, either because `Span::record` sets a field
+/// that already has a value or because an event repeats a key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RepeatedFieldPolicy {
+    /// Keep the most recent value.
+    #[default]
+    LastWins,
+    /// Keep the original value and ignore the rest.
+    FirstWins,
+    /// Keep every value, turning the field into an array once it has been recorded twice.
+    ///
+    /// ```json
+    /// { "state": ["loading", "loaded"] }
+    /// ```
+    Collect,
+    /// Like `Collect`, but remember when each value was recorded, to see how a field changed over
+    /// the life of a span.
+    ///
+    /// ```json
+    /// { "state": [
+    ///     { "timestamp": "2021-04-21T01:02:03.000000001Z", "value": "loading" },
+    ///     { "timestamp": "2021-04-21T01:02:04.000000001Z", "value": "loaded" }
+    /// ] }
+    /// ```
+    History,
+}
+
+/// Whether to merge the fields of every span an event is in, so consumers don't have to search
+/// `spans` for a field like `request_id` that was set on an outer span.
+///
+/// Fields are merged from the root span to the leaf span, so the innermost value wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanContext {
+    /// Don't merge span fields.
+    #[default]
+    None,
+    /// Write the merged fields to a `context` object on each event.
+    ///
+    /// ```json
+    /// { "fields": { "message": "hi" }, "context": { "request_id": "abc", "user_id": 7 } }
+    /// ```
+    Object,
+    /// Merge span fields straight into the event's `fields`. The event's own fields win.
+    ///
+    /// ```json
+    /// { "fields": { "message": "hi", "request_id": "abc", "user_id": 7 } }
+    /// ```
+    Fields,
+}
+
+/// What to do with fields declared on a span with `tracing::field::Empty` that haven't been
+/// recorded yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnsetFields {
+    /// Leave them out, as if they hadn't been declared.
+    #[default]
+    Omit,
+    /// Write them as `null`.
+    Null,
+    /// List their names under `unset_fields` in the span.
+    List,
+}
+
+/// Which spans to write on each event.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanOutput {
+    /// The closest span as `span`, and every span from the root to the leaf as `spans`.
+    #[default]
+    LeafAndAll,
+    /// Just the closest span, as `span`.
+    Leaf,
+    /// Just the outermost span, as `root_span`.
+    Root,
+    /// Every span from the root to the leaf, as `spans`.
+    All,
+    /// The ids of every span from the root to the leaf, as `span_ids`.
+    Ids,
+    /// The closest span's metadata along with the fields of every span merged from the root to the
+    /// leaf, as `span`.
+    Merged,
+    /// No spans at all.
+    None,
+}
 
 impl Default for CustomJsonLayer {
     fn default() -> Self {
-        CustomJsonLayer
+        CustomJsonLayer {
+            make_writer: std::io::stdout,
+            precompute_metadata: true,
+            span_layout: SpanLayout::default(),
+            record: RecordOptions::default(),
+            span_context: SpanContext::default(),
+            uninherited_fields: HashSet::new(),
+            unset_fields: UnsetFields::default(),
+            top_level_message: true,
+            message_templates: false,
+            span_output: [SpanOutput::default(); 5],
+            enrichment: Enrichment::default(),
+            top_level_keys: Vec::new(),
+            pipeline: Pipeline::default(),
+            expand_dotted_fields: false,
+            type_stability: None,
+            cardinality: None,
+            keys: KeyTransform::default(),
+            flight_recorder: None,
+            tail_sampling: None,
+            canonical_lines: None,
+            head_sampling: None,
+            coalescer: None,
+            callsites: CallsiteCache::default(),
+        }
+    }
+}
+
+impl<W> CustomJsonLayer<W>
+where
+    W: for<'w> MakeWriter<'w>,
+{
+    /// Write lines somewhere other than stdout.
+    pub fn with_writer<W2>(mut self, make_writer: W2) -> CustomJsonLayer<W2>
+    where
+        W2: for<'w> MakeWriter<'w> + 'static,
+    {
+        // The layer writes on drop, so its fields have to be taken rather than moved out.
+        use std::mem::take;
+        CustomJsonLayer {
+            make_writer,
+            precompute_metadata: self.precompute_metadata,
+            span_layout: self.span_layout,
+            record: self.record,
+            span_context: self.span_context,
+            uninherited_fields: take(&mut self.uninherited_fields),
+            unset_fields: self.unset_fields,
+            top_level_message: self.top_level_message,
+            message_templates: self.message_templates,
+            span_output: self.span_output,
+            enrichment: take(&mut self.enrichment),
+            top_level_keys: take(&mut self.top_level_keys),
+            pipeline: take(&mut self.pipeline),
+            expand_dotted_fields: self.expand_dotted_fields,
+            type_stability: self.type_stability.take(),
+            cardinality: self.cardinality.take(),
+            keys: take(&mut self.keys),
+            flight_recorder: self.flight_recorder.take(),
+            tail_sampling: self.tail_sampling.take(),
+            canonical_lines: self.canonical_lines.take(),
+            head_sampling: self.head_sampling.take(),
+            coalescer: self.coalescer.take(),
+            callsites: take(&mut self.callsites),
+        }
+    }
+
+    /// Whether to serialize the level, target, and name of each callsite once when it is
+    /// registered (the default), or to serialize them again for every event and span.
+    pub fn with_precomputed_metadata(mut self, precompute_metadata: bool) -> Self {
+        self.precompute_metadata = precompute_metadata;
+        self
+    }
+
+    /// How to lay out span metadata and span fields.
+    pub fn with_span_layout(mut self, span_layout: SpanLayout) -> Self {
+        self.span_layout = span_layout;
+        self
+    }
+
+    /// What to do when a field is recorded more than once.
+    pub fn with_repeated_fields(mut self, repeated_fields: RepeatedFieldPolicy) -> Self {
+        self.record.repeated_fields = repeated_fields;
+        self
+    }
+
+    /// Whether (and where) to merge the fields of every span an event is in.
+    pub fn with_span_context(mut self, span_context: SpanContext) -> Self {
+        self.span_context = span_context;
+        self
+    }
+
+    /// Whether to write span fields declared with `tracing::field::Empty` that haven't been
+    /// recorded, so that a field that was never filled in can be told apart from one that was
+    /// never declared.
+    pub fn with_unset_fields(mut self, unset_fields: UnsetFields) -> Self {
+        self.unset_fields = unset_fields;
+        self
+    }
+
+    /// Span fields that should never be merged into an event's span context.
+    pub fn with_uninherited_fields<I>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.uninherited_fields
+            .extend(fields.into_iter().map(Into::into));
+        self
+    }
+
+    /// Whether to write `message` as a top-level key right after `level` (the default), or to
+    /// leave it in `fields` with everything else.
+    pub fn with_top_level_message(mut self, top_level_message: bool) -> Self {
+        self.top_level_message = top_level_message;
+        self
+    }
+
+    /// Whether to treat messages like `"user {user_id} logged in"` as templates, filling them in
+    /// with the event's own fields. The raw template is kept in `message_template` for grouping.
+    pub fn with_message_templates(mut self, message_templates: bool) -> Self {
+        self.message_templates = message_templates;
+        self
+    }
+
+    /// Write integers bigger than `limit`, in either direction, as strings, so that consumers that
+    /// read numbers as doubles don't lose precision. `MAX_SAFE_INTEGER` is the usual limit. This
+    /// applies to numbers nested in `valuable` values and `SerdeJsonAdapter`s too.
+    pub fn with_large_integer_limit(mut self, limit: u64) -> Self {
+        self.record.numbers.large_integer_limit = Some(limit);
+        self
+    }
+
+    /// How to write `NaN` and infinite floats, which JSON has no way to represent. By default
+    /// they're written as `null`.
+    pub fn with_non_finite_floats(mut self, non_finite_floats: NonFiniteFloats) -> Self {
+        self.record.numbers.non_finite_floats = non_finite_floats;
+        self
+    }
+
+    /// How to write byte slices, like `info!(body = &buf[..])`. By default they're base64.
+    pub fn with_bytes_encoding(mut self, bytes: BytesEncoding) -> Self {
+        self.record.bytes = bytes;
+        self
+    }
+
+    /// Whether to parse values recorded with `Debug` (like `info!(config = ?config)`) into JSON,
+    /// if they look like `#[derive(Debug)]` output of a struct, tuple, list, or map. Anything else,
+    /// including values that don't parse and plain ones like `None` or `"00501"`, is written as a
+    /// string, the same as when this is off.
+    pub fn with_debug_parsing(mut self, parse_debug: bool) -> Self {
+        self.record.parse_debug = parse_debug;
+        self
+    }
+
+    /// Which spans to write on each event.
+    pub fn with_span_output(mut self, span_output: SpanOutput) -> Self {
+        self.span_output = [span_output; 5];
+        self
+    }
+
+    /// Which spans to write on events of a specific level, so that e.g. ERROR lines can carry the
+    /// full scope while DEBUG lines only carry the leaf.
+    pub fn with_span_output_for(mut self, level: Level, span_output: SpanOutput) -> Self {
+        self.span_output[level_index(&level)] = span_output;
+        self
+    }
+
+    /// Add a top-level key with the same value to every line, like `service` or `version`.
+    ///
+    /// # Panics
+    ///
+    /// This and the other `with_*_field` methods panic if `key` is one of the layer's own
+    /// top-level keys, like `level` or `fields`, another enrichment key, or a promotion's key.
+    /// Keys are compared as they're written, after `with_key_rename` and `with_key_case`.
+    pub fn with_static_field(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<serde_json::Value>,
+    ) -> Self {
+        let key = self.add_top_level_key(key.into());
+        self.enrichment.push_static(key, value.into());
+        self
+    }
+
+    /// Add a top-level key to every line with the value of an environment variable, read once.
+    /// If the variable isn't set, the key is left out.
+    pub fn with_env_field(mut self, key: impl Into<String>, var: &str) -> Self {
+        let key = self.add_top_level_key(key.into());
+        self.enrichment.push_env(key, var);
+        self
+    }
+
+    /// Add a top-level key to every line with the (trimmed) contents of a file, read once. This is
+    /// handy for Kubernetes downward-API files. If the file can't be read, the key is left out.
+    pub fn with_file_field(
+        mut self,
+        key: impl Into<String>,
+        path: impl AsRef<std::path::Path>,
+    ) -> Self {
+        let key = self.add_top_level_key(key.into());
+        self.enrichment.push_file(key, path.as_ref());
+        self
+    }
+
+    /// Add a top-level key to every line, calling `provider` for each event to get its value. If
+    /// the provider returns `None`, the key is left out.
+    pub fn with_dynamic_field<F>(mut self, key: impl Into<String>, provider: F) -> Self
+    where
+        F: Fn() -> Option<serde_json::Value> + Send + Sync + 'static,
+    {
+        let key = self.add_top_level_key(key.into());
+        self.enrichment.push_dynamic(key, provider);
+        self
+    }
+
+    /// Like `with_dynamic_field`, but for providers that return a `valuable::Valuable`.
+    pub fn with_dynamic_valuable_field<F, V>(mut self, key: impl Into<String>, provider: F) -> Self
+    where
+        F: Fn() -> Option<V> + Send + Sync + 'static,
+        V: valuable::Valuable,
+    {
+        let key = self.add_top_level_key(key.into());
+        self.enrichment.push_dynamic_valuable(key, provider);
+        self
+    }
+
+    /// Transform events before they're written. Processors run in the order they're added.
+    pub fn with_processor(mut self, processor: impl Processor + 'static) -> Self {
+        self.pipeline.push(Box::new(processor));
+        self
+    }
+
+    /// Only write events that match a filter, like `level >= WARN || fields.user_id == "u_123"`.
+    /// See `filter_expr` for the syntax.
+    ///
+    /// The filter runs as part of the pipeline, so it sees events as the processors added before
+    /// it left them.
+    pub fn with_filter(self, filter: Filter) -> Self {
+        self.with_processor(move |event: &mut EventData| {
+            if filter.matches(event) {
+                Processed::Keep
+            } else {
+                Processed::Drop
+            }
+        })
+    }
+
+    /// Promote a value nested inside a field to a top-level key, like `fields.model/id` to
+    /// `model_id`. Promotions run as part of the pipeline, in the order they're added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the promotion's key is one of the layer's own top-level keys, like `level`, an
+    /// enrichment key, or the key of another promotion, compared as they're written.
+    pub fn with_promotion(mut self, promotion: Promotion) -> Self {
+        self.add_top_level_key(promotion.key().to_owned());
+        self.with_processor(move |event: &mut EventData| promotion.apply(event))
+    }
+
+    /// Expand dotted field names like `http.method` into nested objects, merged with any object
+    /// recorded under the same prefix. This happens after the pipeline and key renaming, so
+    /// processors and filters still see the names as they were recorded. Fields that can't be
+    /// expanded without turning a leaf into an object are left alone and listed in a top-level
+    /// `field_conflicts` key.
+    pub fn with_dotted_field_expansion(mut self, expand_dotted_fields: bool) -> Self {
+        self.expand_dotted_fields = expand_dotted_fields;
+        self
+    }
+
+    /// Remember the JSON type of the first value seen for each field path, either across every
+    /// callsite or for each callsite separately, and deal with later values of a different type
+    /// according to `mismatch`. Useful for log stores that reject documents when a field changes
+    /// type.
+    pub fn with_type_stability(mut self, scope: TypeScope, mismatch: TypeMismatch) -> Self {
+        self.type_stability = Some(TypeStability::new(scope, mismatch));
+        self
+    }
+
+    /// Keep track of the distinct keys seen in each map inside a field, and once a map has had more
+    /// than `limit` of them, write it as `fallback` instead of as an object. Useful for things like
+    /// `BTreeMap`s keyed by IDs, which would otherwise add a new field to the log store's mapping
+    /// for each key. A warning is added to the first event that goes over the limit.
+    pub fn with_cardinality_limit(mut self, limit: usize, fallback: CardinalityFallback) -> Self {
+        self.cardinality = Some(CardinalityGuard::new(limit, fallback));
+        self
+    }
+
+    /// Convert keys to a different naming convention, like camelCase, either for just the
+    /// top-level keys, for field names too, or for every key including those nested in values.
+    pub fn with_key_case(mut self, case: KeyCase, depth: KeyDepth) -> Self {
+        self.keys.set_case(case, depth);
+        self.assert_distinct_top_level_keys();
+        self
+    }
+
+    /// Rename a top-level key or field name, for consumers that expect a legacy name. Renames win
+    /// over `with_key_case`.
+    pub fn with_key_rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.keys.rename(from.into(), to.into());
+        self.assert_distinct_top_level_keys();
+        self
+    }
+
+    /// Remember a key added by enrichment or a promotion, and check that it doesn't clash.
+    fn add_top_level_key(&mut self, key: String) -> String {
+        self.top_level_keys.push(key.clone());
+        self.assert_distinct_top_level_keys();
+        key
+    }
+
+    /// Panic if a configured top-level key would be written under the same name as one of the
+    /// layer's own keys or another configured key, once it has been renamed or had its case
+    /// converted.
+    fn assert_distinct_top_level_keys(&self) {
+        let reserved: HashSet<_> = RESERVED_KEYS
+            .iter()
+            .map(|key| self.keys.top_level(key))
+            .collect();
+        let mut written = HashSet::new();
+        for key in &self.top_level_keys {
+            let key = self.keys.top_level(key);
+            assert!(
+                !reserved.contains(&key),
+                "`{}` is one of the top-level keys the layer writes itself",
+                key
+            );
+            assert!(
+                written.insert(key.clone()),
+                "`{}` is already written as a top-level key",
+                key
+            );
+        }
+    }
+
+    /// Rename the keys of an event's fields, context, and spans, warning about any that kept their
+    /// original names because they would have clashed with another.
+    fn transform_keys(&self, event: &mut EventData, warnings: &mut Vec<String>) {
+        let mut kept: Vec<String> = self
+            .keys
+            .fields(&mut event.fields)
+            .into_iter()
+            .map(|key| format!("fields.{}", key))
+            .collect();
+        if let Some(context) = &mut event.context {
+            kept.extend(
+                self.keys
+                    .fields(context)
+                    .into_iter()
+                    .map(|key| format!("context.{}", key)),
+            );
+        }
+        for span in &mut event.spans {
+            let prefix = format!("spans.{}", span.metadata().name());
+            kept.extend(
+                self.keys
+                    .fields(&mut span.fields)
+                    .into_iter()
+                    .map(|key| format!("{}.{}", prefix, key)),
+            );
+        }
+        warnings.extend(kept.into_iter().map(|key| {
+            format!(
+                "{} kept its name, because renaming it would have clashed with another field",
+                key
+            )
+        }));
+    }
+
+    /// Expand the dotted field names of an event, its context, and its spans, and report any
+    /// conflicts.
+    fn expand_dotted(&self, event: &mut EventData) {
+        let mut conflicts: Vec<serde_json::Value> = dotted::expand(&mut event.fields)
+            .into_iter()
+            .map(|key| format!("fields.{}", key).into())
+            .collect();
+        if let Some(context) = &mut event.context {
+            conflicts.extend(
+                dotted::expand(context)
+                    .into_iter()
+                    .map(|key| format!("context.{}", key).into()),
+            );
+        }
+        for (i, span) in event.spans.iter_mut().enumerate() {
+            conflicts.extend(
+                dotted::expand(&mut span.fields)
+                    .into_iter()
+                    .map(|key| format!("spans[{}].{}", i, key).into()),
+            );
+        }
+        if !conflicts.is_empty() {
+            event
+                .extra
+                .insert("field_conflicts".into(), conflicts.into());
+        }
+    }
+
+    /// Hold on to events that are too verbose to write in a flight recorder, and write them out
+    /// before the next ERROR event (including panics logged by `PanicHook`), marked with
+    /// `"backfilled": true`.
+    pub fn with_flight_recorder(mut self, flight_recorder: FlightRecorder) -> Self {
+        self.flight_recorder = Some(flight_recorder);
+        self
+    }
+
+    /// Hold on to every event of a root span (including the events of the spans inside it) until
+    /// the root span closes, and only write them if it had an ERROR event, was slow, or was
+    /// sampled. Events outside of any span are written straight away.
+    pub fn with_tail_sampling(mut self, tail_sampling: TailSampling) -> Self {
+        self.tail_sampling = Some(tail_sampling);
+        self
+    }
+
+    /// Write a canonical line for spans when they close: one wide line with the span's fields,
+    /// its duration, and a summary of the events inside it.
+    pub fn with_canonical_lines(mut self, canonical_lines: CanonicalLines) -> Self {
+        self.canonical_lines = Some(canonical_lines);
+        self
+    }
+
+    /// Decide whether to write each trace when its root span is created, and write all of it or
+    /// none of it. Events outside of any span are always written.
+    pub fn with_head_sampling(mut self, head_sampling: HeadSampling) -> Self {
+        self.head_sampling = Some(head_sampling);
+        self
+    }
+
+    /// Write only the first of a run of identical events from a callsite, followed by a summary
+    /// with a `repeat_count` and the first and last timestamps. Each thread has its own run, which
+    /// lasts at most `window`, and ends as soon as anything else is logged on that thread. A run
+    /// still going when the layer is dropped has its summary written then.
+    pub fn with_repeat_coalescing(mut self, window: Duration) -> Self {
+        self.coalescer = Some(Coalescer::new(window));
+        self
+    }
+
+    /// Merge the fields of spans, from the root to the leaf, leaving out uninherited fields.
+    fn merge_span_fields(&self, spans: &[SpanView<'_>]) -> Fields {
+        let mut merged = Fields::new();
+        for span in spans {
+            for (k, v) in span.fields {
+                if self.uninherited_fields.contains(k.as_ref()) {
+                    continue;
+                }
+                // An unset field in an inner span mustn't hide a value from an outer one.
+                if span.unset.contains(&k.as_ref()) {
+                    merged.entry(k.clone()).or_insert_with(|| v.clone());
+                } else {
+                    merged.insert(k.clone(), v.clone());
+                }
+            }
+        }
+        merged
+    }
+
+    /// Merge the fields of spans into the event's fields, or return them to go in `context`,
+    /// depending on `SpanContext`.
+    fn span_context(&self, spans: &[SpanView<'_>], fields: &mut Fields) -> Option<Fields> {
+        match self.span_context {
+            SpanContext::None => None,
+            SpanContext::Object => Some(self.merge_span_fields(spans)),
+            SpanContext::Fields => {
+                for (k, v) in self.merge_span_fields(spans) {
+                    fields.entry(k).or_insert(v);
+                }
+                None
+            }
+        }
+    }
+
+    /// Build a span's canonical line as it closes, from the data collected for it.
+    fn collect_canonical_line<S>(
+        &self,
+        canonical_lines: &CanonicalLines,
+        id: &span::Id,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) -> Option<EventData>
+    where
+        S: Subscriber,
+        S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        let span = ctx.span(id)?;
+        let line = span.extensions_mut().remove::<CanonicalLine>()?;
+
+        let (spans, notes, metadata, fields, context) =
+            with_spans(span.scope().from_root(), |spans, notes| {
+                let (leaf, outer) = spans.split_last()?;
+                let mut fields = leaf.fields.clone();
+                let context = self.span_context(outer, &mut fields);
+                let owned = spans.iter().map(SpanView::to_owned).collect();
+                Some((owned, notes, leaf.metadata.clone(), fields, context))
+            })?;
+
+        let mut event = EventData {
+            timestamp: Utc::now(),
+            message: Some(json!(metadata.metadata.name())),
+            metadata,
+            message_template: None,
+            fields,
+            context,
+            spans,
+            extra: canonical_lines.finish(line),
+            stringified: notes.stringified,
+        };
+        add_warnings(&mut event.extra, notes.warnings);
+        Some(event)
+    }
+
+    /// Get the pre-serialized metadata for a callsite, if that's turned on.
+    fn callsite_fragments(
+        &self,
+        metadata: &'static Metadata<'static>,
+    ) -> Option<Arc<CallsiteFragments>> {
+        if self.precompute_metadata {
+            self.callsites.get(metadata).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Whether anything needs an owned copy of each event that it can change or hold on to. If
+    /// not, events are written straight from the data of the spans they're in, without copying it.
+    fn needs_event_data(&self) -> bool {
+        !self.pipeline.is_empty()
+            || self.keys.changes_fields()
+            || self.expand_dotted_fields
+            || self.type_stability.is_some()
+            || self.cardinality.is_some()
+            || self.flight_recorder.is_some()
+            || self.canonical_lines.is_some()
+            || self.coalescer.is_some()
+    }
+
+    /// Record the event's own fields, and pull its message out of them.
+    fn record_event(&self, event: &tracing::Event<'_>) -> RecordedEvent {
+        // Get the data from the event
+        let mut data = CustomLayerTracedData::new(self.record);
+        let mut visitor = JsonAttributeVisitor::with_data(&mut data);
+        event.record(&mut visitor);
+        let warnings: Vec<String> = data.non_finite_warnings("fields").collect();
+        let stringified = data.stringified;
+        let mut fields = data.fields;
+
+        // Pull the message out of the fields, filling in the template if it is one.
+        let mut message_template = None;
+        if self.message_templates {
+            let rendered = fields
+                .get("message")
+                .and_then(|message| message.as_str())
+                .and_then(|template| message::render(template, &fields));
+            if let (Some(rendered), Some(message)) = (rendered, fields.get_mut("message")) {
+                message_template = Some(std::mem::replace(message, json!(rendered)));
+            }
+        }
+        let message = if self.top_level_message {
+            fields.shift_remove("message")
+        } else {
+            None
+        };
+
+        RecordedEvent {
+            timestamp: Utc::now(),
+            metadata: SpanMetadata {
+                metadata: event.metadata(),
+                fragments: self.callsite_fragments(event.metadata()),
+            },
+            message,
+            message_template,
+            fields,
+            warnings,
+            stringified,
+        }
+    }
+
+    /// Collect everything we know about an event, so that the pipeline can have a go at it before
+    /// it's written out.
+    fn collect_event<S>(
+        &self,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) -> EventData
+    where
+        S: Subscriber,
+        S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        let recorded = self.record_event(event);
+        let mut fields = recorded.fields;
+        let mut warnings = recorded.warnings;
+        let mut stringified = recorded.stringified;
+
+        // Copy the data of all the spans we're in, from the outermost to the innermost, and merge
+        // in their fields if that's turned on.
+        let (spans, context) = with_spans(event_scope(event, ctx), |spans, notes| {
+            warnings.extend(notes.warnings);
+            stringified.extend(notes.stringified);
+            let context = self.span_context(spans, &mut fields);
+            (spans.iter().map(SpanView::to_owned).collect(), context)
+        });
+
+        let mut event = EventData {
+            timestamp: recorded.timestamp,
+            metadata: recorded.metadata,
+            message: recorded.message,
+            message_template: recorded.message_template,
+            fields,
+            context,
+            spans,
+            extra: Fields::new(),
+            stringified,
+        };
+        add_warnings(&mut event.extra, warnings);
+        event
+    }
+
+    /// Serialize an event straight from its own data and the data of the spans it's in, returning
+    /// the line and its root span. This is what's written when nothing needs an `EventData`.
+    fn serialize_borrowed<S>(
+        &self,
+        event: &tracing::Event<'_>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) -> Option<(Vec<u8>, Option<span::Id>)>
+    where
+        S: Subscriber,
+        S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        let mut recorded = self.record_event(event);
+        with_spans(event_scope(event, ctx), |spans, notes| {
+            let context = self.span_context(spans, &mut recorded.fields);
+            let mut extra = Fields::new();
+            recorded.warnings.extend(notes.warnings);
+            add_warnings(&mut extra, recorded.warnings);
+
+            let line = self
+                .serialize_view(&EventView {
+                    timestamp: recorded.timestamp,
+                    metadata: &recorded.metadata,
+                    message: recorded.message.as_ref(),
+                    message_template: recorded.message_template.as_ref(),
+                    fields: &recorded.fields,
+                    context: context.as_ref(),
+                    spans,
+                    extra: &extra,
+                })
+                .ok()?;
+            Some((line, spans.first().map(|span| span.id.clone())))
+        })
+    }
+
+    fn serialize_event(&self, event: &EventData) -> Result<Vec<u8>, serde_json::Error> {
+        let spans: Vec<_> = event.spans.iter().map(SpanView::of).collect();
+        self.serialize_view(&EventView {
+            timestamp: event.timestamp,
+            metadata: &event.metadata,
+            message: event.message.as_ref(),
+            message_template: event.message_template.as_ref(),
+            fields: &event.fields,
+            context: event.context.as_ref(),
+            spans: &spans,
+            extra: &event.extra,
+        })
+    }
+
+    // Convenience: if any of the serialization fails, we want to bail. But we don't want to handle
+    // the bail at every location, so we wrap it in a fallible function, and catch the error/bail
+    // in one place.
+    fn span_serializer<'a>(&'a self, span: &SpanView<'a>) -> SpanSerializer<'a> {
+        SpanSerializer {
+            metadata: span.metadata,
+            fields: span.fields,
+            layout: self.span_layout,
+            unset: match self.unset_fields {
+                UnsetFields::List => span.unset,
+                _ => &[],
+            },
+            keys: &self.keys,
+        }
+    }
+
+    fn serialize_view(&self, event: &EventView<'_>) -> Result<Vec<u8>, serde_json::Error> {
+        // OK, so it would be easier to just build up a big `serde_json::Value` and then output
+        // it. However, that would end up with a weird order for the fields. And since these
+        // things show up in CloudWatch for us, we kinda want the most important data in the
+        // front.
+        //
+        // So instead we create a `serde_json::Serializer` and serialize a bit more manually.
+
+        let mut serializer = serde_json::Serializer::new(vec![]);
+        let mut map_serializer = serializer.serialize_map(None)?;
+        // ```
+        // {
+        //   "timestamp": "2021-04-21T01:02:03.000000001Z",
+        //   "level": "INFO",
+        //   "message": "user 17 logged in",
+        //   "message_template": "user {user_id} logged in",
+        //   "target": "word_notifier::module::submodule",
+        //   "service": "word_notifier",
+        //   "model_id": "abc",
+        //   "fields": {
+        //     "some_field": "a string",
+        //     "another_field": 17
+        //   }
+        //   ...
+        // }
+        let key = |key| self.keys.top_level(key);
+        map_serializer.serialize_entry(&key("timestamp"), &event.timestamp)?;
+        event
+            .metadata
+            .serialize_level(&mut map_serializer, &key("level"))?;
+        if let Some(message) = event.message {
+            map_serializer.serialize_entry(&key("message"), message)?;
+        }
+        if let Some(message_template) = event.message_template {
+            map_serializer.serialize_entry(&key("message_template"), message_template)?;
+        }
+        event
+            .metadata
+            .serialize_target(&mut map_serializer, &key("target"))?;
+        self.enrichment.serialize(&mut map_serializer, &self.keys)?;
+        for (k, value) in event.extra {
+            map_serializer.serialize_entry(&key(k), value)?;
+        }
+        map_serializer.serialize_entry(&key("fields"), event.fields)?;
+
+        // If we're merging span fields into a context object, that comes next.
+        //
+        // ```
+        // {
+        //   ...
+        //   "context": {
+        //     "request_id": "abc",
+        //     "some_field": 1
+        //   }
+        //   ...
+        // }
+        // ```
+        if let Some(context) = event.context {
+            map_serializer.serialize_entry(&key("context"), context)?;
+        }
+
+        let span_output = self.span_output[level_index(event.metadata.metadata.level())];
+
+        // If we are in a span, get the closest span and log out it.
+        //
+        // ```
+        // {
+        //   ...
+        //   "span": {
+        //     "target": "zenlist_core::client::actions::get",
+        //     "name": "get_option",
+        //     "some_field": 1
+        //   }
+        //   ...
+        // }
+        // ```
+        if matches!(span_output, SpanOutput::LeafAndAll | SpanOutput::Leaf) {
+            if let Some(span) = event.spans.last() {
+                map_serializer.serialize_entry(&key("span"), &self.span_serializer(span))?;
+            }
+        }
+
+        // Or the same thing, but with the fields of all the spans we're in merged together.
+        if span_output == SpanOutput::Merged {
+            if let Some(span) = event.spans.last() {
+                let fields = self.merge_span_fields(event.spans);
+                let merged = SpanSerializer {
+                    fields: &fields,
+                    ..self.span_serializer(span)
+                };
+                map_serializer.serialize_entry(&key("span"), &merged)?;
+            }
+        }
+
+        // Or the outermost span, which is usually the one that describes the whole request.
+        //
+        // ```
+        // {
+        //   ...
+        //   "root_span": { "target": "...", "name": "request", "request_id": "abc" }
+        //   ...
+        // }
+        // ```
+        if span_output == SpanOutput::Root {
+            if let Some(span) = event.spans.first() {
+                map_serializer.serialize_entry(&key("root_span"), &self.span_serializer(span))?;
+            }
+        }
+
+        // Also if we're in a span, get the whole stack of spans we're in and log them
+        //
+        // ```
+        // {
+        //   ...
+        //   "spans": [
+        //     { "target": "...", "name": "...", "some_field": 1 }, // outermost span
+        //     { "target": "...", "name": "...", "a_thing": true },
+        //     { "target": "...", "name": "...", "different_field": 1, "enabled": true }  // innermost span
+        //   ]
+        //   ...
+        // }
+        // ```
+        if matches!(span_output, SpanOutput::LeafAndAll | SpanOutput::All)
+            && !event.spans.is_empty()
+        {
+            let spans: Vec<_> = event
+                .spans
+                .iter()
+                .map(|span| self.span_serializer(span))
+                .collect();
+            map_serializer.serialize_entry(&key("spans"), &spans)?;
+        }
+
+        // Or just the ids of those spans, from the outermost to the innermost.
+        //
+        // ```
+        // {
+        //   ...
+        //   "span_ids": [1, 2, 3]
+        //   ...
+        // }
+        // ```
+        if span_output == SpanOutput::Ids && !event.spans.is_empty() {
+            let ids: Vec<u64> = event.spans.iter().map(|span| span.id.into_u64()).collect();
+            map_serializer.serialize_entry(&key("span_ids"), &ids)?;
+        }
+
+        SerializeMap::end(map_serializer)?;
+        let mut inner = serializer.into_inner();
+        inner.push(b'\n');
+        Ok(inner)
     }
 }
 
-impl<S> Layer<S> for CustomJsonLayer
+impl<W> CustomJsonLayer<W>
+where
+    W: for<'w> MakeWriter<'w>,
+{
+    /// Write a line, ignoring any errors, since there's nowhere to report them.
+    fn write(&self, metadata: &Metadata<'_>, line: &[u8]) {
+        let mut writer = self.make_writer.make_writer_for(metadata);
+        if writer.write_all(line).is_ok() {
+            let _ = writer.flush();
+        }
+    }
+
+    /// Write a line, or put it in its root span's tail sampling buffer if it has one.
+    fn emit<S>(
+        &self,
+        root: Option<&span::Id>,
+        metadata: &'static Metadata<'static>,
+        line: Vec<u8>,
+        ctx: &tracing_subscriber::layer::Context<'_, S>,
+    ) where
+        S: Subscriber,
+        S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        if let (Some(tail), Some(span)) = (&self.tail_sampling, root.and_then(|id| ctx.span(id))) {
+            if let Some(buffer) = span.extensions_mut().get_mut::<TailBuffer>() {
+                tail.buffer(buffer, metadata, line);
+                return;
+            }
+        }
+        self.write(metadata, &line);
+    }
+
+    /// Run an event through the pipeline and everything after it, and write it out.
+    fn process<S>(&self, collected: EventData, ctx: &tracing_subscriber::layer::Context<'_, S>)
+    where
+        S: Subscriber,
+        S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    {
+        for event in self.prepare(collected) {
+            let root = event.spans.first().map(|span| span.id.clone());
+            self.finish(event, root, &mut |root, metadata, line| {
+                self.emit(root, metadata, line, ctx)
+            });
+        }
+    }
+
+    /// Run an event through the pipeline and rename its keys, returning whatever events are left.
+    /// This is everything that changes what an event says, rather than how it's written.
+    fn prepare(&self, collected: EventData) -> Vec<EventData> {
+        // Let the pipeline have a go at it...
+        let mut events = self.pipeline.run(collected);
+
+        // ...and now that it's done with them, rename the keys if we need to.
+        for event in &mut events {
+            let mut warnings = Vec::new();
+            self.transform_keys(event, &mut warnings);
+            add_warnings(&mut event.extra, warnings);
+        }
+        events
+    }
+
+    /// Write out an event that's been through `prepare`, handing the lines to `emit` along with
+    /// their root span. That's usually the event's first span, but `None` for an event whose spans
+    /// may have closed, whose ids could belong to other spans by now.
+    fn finish(&self, mut event: EventData, root: Option<span::Id>, emit: &mut Emit<'_>) {
+        // Expand dotted names, which converts each part of the name the same way...
+        if self.expand_dotted_fields {
+            self.expand_dotted(&mut event);
+        }
+
+        // ...and keep the types of fields stable and maps with too many keys in check.
+        let mut warnings = Vec::new();
+        if let Some(stability) = &self.type_stability {
+            check_types(stability, &mut event, &mut warnings);
+        }
+        if let Some(cardinality) = &self.cardinality {
+            guard_cardinality(cardinality, &mut event, &mut warnings);
+        }
+        add_warnings(&mut event.extra, warnings);
+
+        // If the event is too verbose to write, it goes in the flight recorder instead...
+        let recorder = self.flight_recorder.as_ref();
+        let level = event.metadata().level();
+        if let Some(recorder) = recorder.filter(|recorder| recorder.records(level)) {
+            event
+                .extra
+                .insert("backfilled".into(), serde_json::Value::Bool(true));
+            if let Ok(serialized) = self.serialize_event(&event) {
+                recorder.record(recorder.key(root.as_ref()), event.metadata(), serialized);
+            }
+            return;
+        }
+
+        // Create the JSON representation of the event...
+        let serialized = match self.serialize_event(&event) {
+            Ok(serialized) => serialized,
+            Err(_) => return,
+        };
+
+        // ...write out whatever the flight recorder has been holding on to, if it's an error...
+        if let Some(recorder) = recorder.filter(|_| *level == Level::ERROR) {
+            for recorded in recorder.take(&recorder.key(root.as_ref())) {
+                emit(root.as_ref(), recorded.metadata, recorded.line);
+            }
+        }
+
+        // And write it out (or hold on to it until its root span closes)!
+        emit(root.as_ref(), event.metadata(), serialized);
+    }
+}
+
+/// Where `finish` hands each line, along with its metadata and its root span.
+type Emit<'a> = dyn FnMut(Option<&span::Id>, &'static Metadata<'static>, Vec<u8>) + 'a;
+
+impl<W> Drop for CustomJsonLayer<W>
+where
+    W: for<'w> MakeWriter<'w>,
+{
+    fn drop(&mut self) {
+        // The summaries of runs of repeats that are still going haven't been written, and this is
+        // the last chance to. Their spans are gone, so they're written straight out.
+        let summaries = self.coalescer.as_ref().map(Coalescer::finish);
+        for summary in summaries.into_iter().flatten() {
+            self.finish(summary, None, &mut |_, metadata, line| {
+                self.write(metadata, &line)
+            });
+        }
+    }
+}
+
+impl<S, W> Layer<S> for CustomJsonLayer<W>
 where
     S: Subscriber,
     S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
+    W: for<'w> MakeWriter<'w> + 'static,
 {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        // This is called once per callsite, so it's a good time to do any work that only depends
+        // on the callsite's metadata.
+        if self.precompute_metadata {
+            self.callsites.register(metadata);
+        }
+        Interest::always()
+    }
+
     fn on_new_span(
         &self,
         attrs: &span::Attributes<'_>,
@@ -65,13 +1250,43 @@ where
         // on the span itself.
 
         if let Some(span) = ctx.span(id) {
-            let mut data = CustomLayerTracedData::default();
+            let mut data = CustomLayerTracedData::new(self.record);
             let mut visitor = JsonAttributeVisitor::with_data(&mut data);
-            visitor.record_metadata(span.metadata());
+            visitor.record_metadata(span.metadata(), self.callsite_fragments(span.metadata()));
             attrs.record(&mut visitor);
+            data.track_unset(span.metadata().fields(), self.unset_fields);
+
+            // Decide whether to write the trace if this is its root, or go along with the decision
+            // made for its root if it isn't. Spans that won't be written don't need anything else.
+            if let Some(head_sampling) = &self.head_sampling {
+                let sampled = match span.parent() {
+                    Some(parent) => parent
+                        .extensions()
+                        .get::<Sampled>()
+                        .is_none_or(|sampled| sampled.0),
+                    None => head_sampling.sample(span.metadata(), &data.fields).0,
+                };
+                span.extensions_mut().insert(Sampled(sampled));
+                if !sampled {
+                    return;
+                }
+            }
 
             let mut extensions = span.extensions_mut();
             extensions.insert(data);
+
+            if let Some(canonical_lines) = &self.canonical_lines {
+                if canonical_lines.selects(span.metadata()) {
+                    extensions.insert(canonical_lines.start());
+                }
+            }
+
+            // Root spans hold the events of everything inside them until they close.
+            if let Some(tail) = &self.tail_sampling {
+                if span.parent().is_none() {
+                    extensions.insert(tail.start());
+                }
+            }
         }
     }
 
@@ -97,104 +1312,77 @@ where
         // An event (created by e.g. `tracing::info!(blah = 3)`) has been created. This is our
         // chance to shine by outputting some JSON to stdout!
 
-        // Convenience: if any of the serialization fails, we want to bail. But we don't want to
-        // handle the bail at every location, so we wrap it in a fallible function, and catch the
-        // error/bail in one place.
-        fn serialize_on_event<S>(
-            event: &tracing::Event<'_>,
-            ctx: tracing_subscriber::layer::Context<'_, S>,
-        ) -> Result<Vec<u8>, serde_json::Error>
-        where
-            S: Subscriber,
-            S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
-        {
-            // Get the data from the event
-            let mut data = CustomLayerTracedData::default();
-            let mut visitor = JsonAttributeVisitor::with_data(&mut data);
-            event.record(&mut visitor);
-
-            // OK, so it would be easier to just build up a big `serde_json::Value` and then output
-            // it. However, that would end up with a weird order for the fields. And since these
-            // things show up in CloudWatch for us, we kinda want the most important data in the
-            // front.
-            //
-            // So instead we create a `serde_json::Serializer` and serialize a bit more manually.
-
-            let mut serializer = serde_json::Serializer::new(vec![]);
-            let mut map_serializer = serializer.serialize_map(None)?;
-            // ```
-            // {
-            //   "timestamp": "2021-04-21T01:02:03.000000001Z",
-            //   "level": "INFO",
-            //   "target": "word_notifier::module::submodule",
-            //   "fields": {
-            //     "some_field": "a string",
-            //     "another_field": 17
-            //   }
-            //   ...
-            // }
-            map_serializer.serialize_entry("timestamp", &json!(Utc::now()))?;
-            map_serializer
-                .serialize_entry("level", &json!(format_level(event.metadata().level())))?;
-            map_serializer.serialize_entry("target", &json!(event.metadata().target()))?;
-            map_serializer.serialize_entry("fields", &data)?;
-
-            // If we are in a span, get the closest span and log out it.
-            //
-            // ```
-            // {
-            //   ...
-            //   "span": {
-            //     "target": "zenlist_core::client::actions::get",
-            //     "name": "get_option",
-            //     "some_field": 1
-            //   }
-            //   ...
-            // }
-            // ```
-            if let Some(span) = ctx.event_span(event) {
-                if let Some(data) = span.extensions().get::<CustomLayerTracedData>() {
-                    map_serializer.serialize_entry("span", data)?;
+        // If the trace it's in isn't being written, there's nothing to do.
+        if let Some(span) = ctx.event_span(event) {
+            if matches!(span.extensions().get::<Sampled>(), Some(Sampled(false))) {
+                return;
+            }
+        }
+
+        // If nothing needs its own copy of the event, write it straight from the data we have.
+        if !self.needs_event_data() {
+            if let Some((line, root)) = self.serialize_borrowed(event, &ctx) {
+                self.emit(root.as_ref(), event.metadata(), line, &ctx);
+            }
+            return;
+        }
+
+        // Otherwise collect the event and let the pipeline have a go at it...
+        let collected = self.collect_event(event, &ctx);
+        let mut emit =
+            |root: Option<&span::Id>, metadata, line| self.emit(root, metadata, line, &ctx);
+        for event in self.prepare(collected) {
+            // ...count it towards the canonical lines of the spans it's in, as it'll be written...
+            if let Some(canonical_lines) = &self.canonical_lines {
+                for span in event.spans.iter().filter_map(|span| ctx.span(&span.id)) {
+                    if let Some(line) = span.extensions_mut().get_mut::<CanonicalLine>() {
+                        canonical_lines.observe(line, &event, &self.keys);
+                    }
                 }
             }
 
-            // Also if we're in a span, get the whole stack of spans we're in and log them
-            //
-            // ```
-            // {
-            //   ...
-            //   "spans": [
-            //     { "target": "...", "name": "...", "some_field": 1 }, // outermost span
-            //     { "target": "...", "name": "...", "a_thing": true },
-            //     { "target": "...", "name": "...", "different_field": 1, "enabled": true }  // innermost span
-            //   ]
-            //   ...
-            // }
-            // ```
-            if let Some(scope) = ctx.event_scope(event) {
-                let scope_serializer = ScopeSerializer::new(scope);
-                map_serializer.serialize_entry("spans", &scope_serializer)?;
+            // ...hold back repeats of the same event...
+            if let Some(coalescer) = &self.coalescer {
+                match coalescer.coalesce(&event) {
+                    Coalesced::Repeat => continue,
+                    Coalesced::Write(summaries) => {
+                        for summary in summaries {
+                            self.finish(summary, None, &mut emit);
+                        }
+                    }
+                }
             }
 
-            SerializeMap::end(map_serializer)?;
-            let mut inner = serializer.into_inner();
-            inner.push(b'\n');
-            Ok(inner)
+            // ...and write it out.
+            let root = event.spans.first().map(|span| span.id.clone());
+            self.finish(event, root, &mut emit);
         }
+    }
 
-        // Create the JSON representation of the event...
-        let serialized = match serialize_on_event(event, ctx) {
-            Ok(serialized) => serialized,
-            Err(_) => return,
-        };
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if let Some(recorder) = &self.flight_recorder {
+            recorder.close(&id);
+        }
 
-        // And write it to stdout!
-        let mut stdout = std::io::stdout();
-        match stdout.write_all(&serialized) {
-            Ok(_) => {}
-            Err(_) => return,
+        // The span's canonical line is written like any other event, so if it's a root span with
+        // tail sampling, it's one of the events that might be written below.
+        if let Some(canonical_lines) = &self.canonical_lines {
+            if let Some(line) = self.collect_canonical_line(canonical_lines, &id, &ctx) {
+                self.process(line, &ctx);
+            }
+        }
+
+        // A root span has closed, so decide whether to write the events it was holding on to.
+        if let Some(tail) = &self.tail_sampling {
+            let buffer = ctx
+                .span(&id)
+                .and_then(|span| span.extensions_mut().remove::<TailBuffer>());
+            if let Some(lines) = buffer.and_then(|buffer| tail.finish(buffer)) {
+                for (metadata, line) in lines {
+                    self.write(metadata, &line);
+                }
+            }
         }
-        let _ = stdout.flush();
     }
 }
 
@@ -214,24 +1402,57 @@ impl<'a> JsonAttributeVisitor<'a> {
         self.0
     }
 
-    /// Add `target` and `name` to the JSON data that is stored.
-    fn record_metadata(&mut self, metadata: &Metadata) {
-        let data = self.data_mut();
-        data.insert("target", json!(metadata.target()));
-        data.insert("name", json!(metadata.name()));
+    /// Store the span's metadata (`target` and `name`) alongside the JSON data.
+    ///
+    /// This is kept apart from the fields, so that a field called `name` can't overwrite the
+    /// span's actual name.
+    fn record_metadata(
+        &mut self,
+        metadata: &'static Metadata<'static>,
+        fragments: Option<Arc<CallsiteFragments>>,
+    ) {
+        self.data_mut().metadata = Some(SpanMetadata {
+            metadata,
+            fragments,
+        });
     }
 }
 
 impl<'a> Visit for JsonAttributeVisitor<'a> {
     fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-        self.data_mut().insert(field.name(), json!(value));
+        let (value, warn) = self.data_mut().options.numbers.f64(value);
+        if warn {
+            self.data_mut().non_finite.push(field.name());
+        }
+        self.data_mut().insert(field.name(), value);
     }
 
     fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-        self.data_mut().insert(field.name(), json!(value));
+        let data = self.data_mut();
+        let value = data.options.numbers.i64(value, &mut data.stringified);
+        data.insert(field.name(), value);
     }
 
     fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        let data = self.data_mut();
+        let value = data.options.numbers.u64(value, &mut data.stringified);
+        data.insert(field.name(), value);
+    }
+
+    fn record_i128(&mut self, field: &tracing::field::Field, value: i128) {
+        let data = self.data_mut();
+        let value = data.options.numbers.i128(value, &mut data.stringified);
+        data.insert(field.name(), value);
+    }
+
+    fn record_u128(&mut self, field: &tracing::field::Field, value: u128) {
+        let data = self.data_mut();
+        let value = data.options.numbers.u128(value, &mut data.stringified);
+        data.insert(field.name(), value);
+    }
+
+    fn record_bytes(&mut self, field: &tracing::field::Field, value: &[u8]) {
+        let value = self.data_mut().options.bytes.encode(value);
         self.data_mut().insert(field.name(), json!(value));
     }
 
@@ -244,7 +1465,9 @@ impl<'a> Visit for JsonAttributeVisitor<'a> {
         // we're performing, parse the rest of the string as JSON and use that. Otherwise, it's just
         // a regular string.
         let data = if let Some(json_str) = value.strip_prefix(SPECIAL_JSON_PREFIX) {
-            if let Ok(json) = serde_json::from_str(json_str) {
+            if let Ok(mut json) = serde_json::from_str(json_str) {
+                let data = self.data_mut();
+                data.options.numbers.json(&mut json, &mut data.stringified);
                 json
             } else {
                 json!(value)
@@ -265,86 +1488,416 @@ impl<'a> Visit for JsonAttributeVisitor<'a> {
     }
 
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        self.data_mut()
-            .insert(field.name(), json!(format!("{:?}", value)));
+        let debug = format!("{:?}", value);
+
+        // The message is recorded as `Debug` too, but it's text, even if it happens to look like
+        // a number or a name. So are `Display` values like `zip = %"00501"`, which can't be told
+        // apart from `Debug` ones, so only structs, tuples, lists, and maps are parsed.
+        let options = self.data_mut().options;
+        if options.parse_debug && field.name() != "message" {
+            let parsed = debug_json::parse(&debug).ok();
+            if let Some(mut json) = parsed.filter(|json| json.is_object() || json.is_array()) {
+                options
+                    .numbers
+                    .json(&mut json, &mut self.data_mut().stringified);
+                self.data_mut().insert(field.name(), json);
+                return;
+            }
+        }
+        self.data_mut().insert(field.name(), json!(debug));
     }
 
     fn record_value(&mut self, field: &tracing::field::Field, value: valuable::Value<'_>) {
-        self.data_mut().insert(
-            field.name(),
-            json!(valuable_serde::Serializable::new(value)),
-        );
+        let numbers = self.data_mut().options.numbers;
+        let mut to_json = ToJson::new(&numbers);
+        let value = to_json.convert(value);
+        if to_json.non_finite {
+            self.data_mut().non_finite.push(field.name());
+        }
+        self.data_mut().stringified.extend(to_json.stringified);
+        self.data_mut().insert(field.name(), value);
     }
 }
 
-/// Data from traced spans that gets stored as extensions inside tracing spans, and can be
-/// serialized into the data we want to show.
-#[derive(Default)]
-struct CustomLayerTracedData(IndexMap<&'static str, serde_json::Value>);
+/// How the values of fields are turned into JSON as they're recorded.
+#[derive(Clone, Copy, Default)]
+struct RecordOptions {
+    repeated_fields: RepeatedFieldPolicy,
+    numbers: NumericPolicy,
+    bytes: BytesEncoding,
+    parse_debug: bool,
+}
+
+/// Data from traced spans that gets stored as extensions inside tracing spans, and is copied into
+/// the `EventData` of every event inside them.
+struct CustomLayerTracedData {
+    /// Only set for spans.
+    metadata: Option<SpanMetadata>,
+    options: RecordOptions,
+    fields: Fields,
+    /// Fields that have already been turned into an array by `Collect` or `History`.
+    repeated: HashSet<&'static str>,
+    /// When each field was first recorded. Only tracked for `History`.
+    recorded_at: HashMap<&'static str, DateTime<Utc>>,
+    /// Fields that had a non-finite float written as `null`, to be warned about.
+    non_finite: Vec<&'static str>,
+    /// The integers the numeric policy wrote as strings.
+    stringified: Stringified,
+    /// Fields declared on a span that haven't been recorded yet. Only tracked if `UnsetFields`
+    /// isn't `Omit`.
+    unset: Vec<&'static str>,
+}
+
+/// The metadata of an event or span, pre-serialized if the layer is configured that way.
+#[derive(Clone)]
+pub(crate) struct SpanMetadata {
+    metadata: &'static Metadata<'static>,
+    fragments: Option<Arc<CallsiteFragments>>,
+}
+
+impl SpanMetadata {
+    /// Whether a field name would collide with one of the metadata keys.
+    fn is_reserved(key: &str) -> bool {
+        key == "target" || key == "name"
+    }
+
+    fn serialize_level<M: SerializeMap>(&self, map: &mut M, key: &str) -> Result<(), M::Error> {
+        match &self.fragments {
+            Some(fragments) => map.serialize_entry(key, &*fragments.level),
+            None => map.serialize_entry(key, format_level(self.metadata.level())),
+        }
+    }
+
+    fn serialize_target<M: SerializeMap>(&self, map: &mut M, key: &str) -> Result<(), M::Error> {
+        match &self.fragments {
+            Some(fragments) => map.serialize_entry(key, &*fragments.target),
+            None => map.serialize_entry(key, self.metadata.target()),
+        }
+    }
+
+    fn serialize_name<M: SerializeMap>(&self, map: &mut M, key: &str) -> Result<(), M::Error> {
+        match &self.fragments {
+            Some(fragments) => map.serialize_entry(key, &*fragments.name),
+            None => map.serialize_entry(key, self.metadata.name()),
+        }
+    }
+}
 
 impl CustomLayerTracedData {
-    pub fn insert(
-        &mut self,
-        key: &'static str,
-        value: serde_json::Value,
-    ) -> Option<serde_json::Value> {
-        self.0.insert(key, value)
+    pub fn new(options: RecordOptions) -> Self {
+        CustomLayerTracedData {
+            metadata: None,
+            options,
+            fields: IndexMap::new(),
+            repeated: HashSet::new(),
+            recorded_at: HashMap::new(),
+            non_finite: Vec::new(),
+            stringified: Stringified::new(),
+            unset: Vec::new(),
+        }
     }
+
+    /// Keep track of the span's declared fields that haven't been recorded, writing them as
+    /// `null` if that's what `unset_fields` asks for. Fields stay in the order they were declared.
+    fn track_unset(&mut self, declared: &tracing::field::FieldSet, unset_fields: UnsetFields) {
+        if unset_fields == UnsetFields::Omit {
+            return;
+        }
+        let mut fields = Fields::with_capacity(declared.len());
+        for field in declared {
+            let name = field.name();
+            match self.fields.shift_remove(name) {
+                Some(value) => {
+                    fields.insert(Cow::Borrowed(name), value);
+                }
+                None => {
+                    self.unset.push(name);
+                    if unset_fields == UnsetFields::Null {
+                        fields.insert(Cow::Borrowed(name), serde_json::Value::Null);
+                    }
+                }
+            }
+        }
+        self.fields = fields;
+    }
+
+    /// Warnings about any fields that had a non-finite float written as `null`.
+    fn non_finite_warnings(&self, prefix: &str) -> impl Iterator<Item = String> + '_ {
+        let prefix = prefix.to_owned();
+        self.non_finite.iter().map(move |field| {
+            format!(
+                "{}.{} had a NaN or infinite number, which was written as null",
+                prefix, field
+            )
+        })
+    }
+
+    /// Record a field, following the `RepeatedFieldPolicy` if it already has a value.
+    pub fn insert(&mut self, key: &'static str, value: serde_json::Value) {
+        // The first time an unset field is recorded, it replaces the placeholder, if there is one.
+        if let Some(index) = self.unset.iter().position(|unset| *unset == key) {
+            self.unset.remove(index);
+            if let Some(placeholder) = self.fields.get_mut(key) {
+                *placeholder = value;
+                if self.options.repeated_fields == RepeatedFieldPolicy::History {
+                    self.recorded_at.insert(key, Utc::now());
+                }
+                return;
+            }
+        }
+
+        let existing = match self.fields.get_mut(key) {
+            Some(existing) => existing,
+            None => {
+                if self.options.repeated_fields == RepeatedFieldPolicy::History {
+                    self.recorded_at.insert(key, Utc::now());
+                }
+                self.fields.insert(Cow::Borrowed(key), value);
+                return;
+            }
+        };
+
+        let value = match self.options.repeated_fields {
+            RepeatedFieldPolicy::LastWins => {
+                *existing = value;
+                return;
+            }
+            RepeatedFieldPolicy::FirstWins => return,
+            RepeatedFieldPolicy::Collect => value,
+            RepeatedFieldPolicy::History => json!({ "timestamp": Utc::now(), "value": value }),
+        };
+
+        if self.repeated.contains(key) {
+            if let Some(values) = existing.as_array_mut() {
+                values.push(value);
+            }
+        } else {
+            let mut first = existing.take();
+            if let Some(recorded_at) = self.recorded_at.get(key) {
+                first = json!({ "timestamp": recorded_at, "value": first });
+            }
+            *existing = json!([first, value]);
+            self.repeated.insert(key);
+        }
+    }
+}
+
+/// What's written for an event, borrowed either from an `EventData` or, when nothing needs one,
+/// straight from the event and the spans it's in.
+struct EventView<'a> {
+    timestamp: DateTime<Utc>,
+    metadata: &'a SpanMetadata,
+    message: Option<&'a serde_json::Value>,
+    message_template: Option<&'a serde_json::Value>,
+    fields: &'a Fields,
+    context: Option<&'a Fields>,
+    spans: &'a [SpanView<'a>],
+    extra: &'a Fields,
+}
+
+/// What's written for one of the spans an event is in, borrowed either from a `SpanData` or from
+/// the span's own data.
+struct SpanView<'a> {
+    id: span::Id,
+    metadata: &'a SpanMetadata,
+    fields: &'a Fields,
+    unset: &'a [&'static str],
+}
+
+impl<'a> SpanView<'a> {
+    fn of(span: &'a SpanData) -> Self {
+        SpanView {
+            id: span.id.clone(),
+            metadata: &span.metadata,
+            fields: &span.fields,
+            unset: &span.unset,
+        }
+    }
+
+    /// Copy the span's data, for an `EventData`.
+    fn to_owned(&self) -> SpanData {
+        SpanData {
+            id: self.id.clone(),
+            metadata: self.metadata.clone(),
+            fields: self.fields.clone(),
+            unset: self.unset.to_vec(),
+        }
+    }
+}
+
+/// An event's own data, before it's put together with the data of its spans.
+struct RecordedEvent {
+    timestamp: DateTime<Utc>,
+    metadata: SpanMetadata,
+    message: Option<serde_json::Value>,
+    message_template: Option<serde_json::Value>,
+    fields: Fields,
+    warnings: Vec<String>,
+    stringified: Stringified,
+}
+
+/// Serialize a span's metadata and fields using the configured `SpanLayout`.
+struct SpanSerializer<'a> {
+    metadata: &'a SpanMetadata,
+    fields: &'a Fields,
+    layout: SpanLayout,
+    /// Written as `unset_fields`, if there are any.
+    unset: &'a [&'static str],
+    /// The keys of span objects are converted like field names.
+    keys: &'a KeyTransform,
 }
 
-impl serde::Serialize for CustomLayerTracedData {
+impl<'a> serde::Serialize for SpanSerializer<'a> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut map = serializer.serialize_map(Some(self.0.len()))?;
-        for (k, v) in &self.0 {
-            map.serialize_entry(k, v)?;
+        let key = |key| self.keys.span_key(key);
+        let mut map = serializer.serialize_map(None)?;
+        match self.layout {
+            SpanLayout::Flat => {
+                self.metadata.serialize_target(&mut map, &key("target"))?;
+                self.metadata.serialize_name(&mut map, &key("name"))?;
+                for (k, v) in self.fields {
+                    if SpanMetadata::is_reserved(k) {
+                        map.serialize_entry(&format!("{}.{}", key("fields"), k), v)?;
+                    } else {
+                        map.serialize_entry(k, v)?;
+                    }
+                }
+            }
+            SpanLayout::Nested => {
+                self.metadata.serialize_name(&mut map, &key("name"))?;
+                self.metadata.serialize_target(&mut map, &key("target"))?;
+                map.serialize_entry(&key("fields"), self.fields)?;
+            }
+        }
+        if !self.unset.is_empty() {
+            let unset: Vec<_> = self
+                .unset
+                .iter()
+                .map(|name| self.keys.field_name(name))
+                .collect();
+            map.serialize_entry(&key("unset_fields"), &unset)?;
         }
         map.end()
     }
 }
 
-/// Serialize all of the parent spans of an event as JSON data.
-//
-// Woah! `Cell<Option<Scope<'a, R>>>`? That's complicated.
-//
-// It turns out that the scope needs to be _owned_ to get anything valuable out of it. We own it
-// when we create this struct, but `serde::Serialize::serialize` is called with a reference. Making
-// it a `Cell<Option<..>>` means that when `serde::Serialize::serialize` is called, we can take
-// ownership of it so we can serialize what we need to.
-struct ScopeSerializer<'a, R: tracing_subscriber::registry::LookupSpan<'a>>(
-    Cell<Option<Scope<'a, R>>>,
-);
+/// Check the types of an event's fields, context, and spans.
+fn check_types(stability: &TypeStability, event: &mut EventData, warnings: &mut Vec<String>) {
+    let callsite = event.metadata().callsite();
+    let stringified = &event.stringified;
+    let mut check = |callsite, prefix: &str, fields: &mut Fields| {
+        stability.check(callsite, prefix, fields, stringified, warnings);
+    };
+    check(callsite.clone(), "fields", &mut event.fields);
+    if let Some(context) = &mut event.context {
+        check(callsite, "context", context);
+    }
+    for span in &mut event.spans {
+        let prefix = format!("spans.{}", span.metadata().name());
+        check(span.metadata().callsite(), &prefix, &mut span.fields);
+    }
+}
 
-impl<'a, R> ScopeSerializer<'a, R>
+/// Guard the maps in an event's fields, context, and spans.
+fn guard_cardinality(
+    cardinality: &CardinalityGuard,
+    event: &mut EventData,
+    warnings: &mut Vec<String>,
+) {
+    cardinality.guard("fields", &mut event.fields, warnings);
+    if let Some(context) = &mut event.context {
+        cardinality.guard("context", context, warnings);
+    }
+    for span in &mut event.spans {
+        let prefix = format!("spans.{}", span.metadata().name());
+        cardinality.guard(&prefix, &mut span.fields, warnings);
+    }
+}
+
+/// Add warnings about how an event was written to a top-level `warnings` key.
+fn add_warnings(extra: &mut Fields, warnings: Vec<String>) {
+    if warnings.is_empty() {
+        return;
+    }
+    let entry = extra
+        .entry("warnings".into())
+        .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+    if let serde_json::Value::Array(existing) = entry {
+        existing.extend(warnings.into_iter().map(serde_json::Value::from));
+    }
+}
+
+/// The spans an event is in, from the root to the leaf.
+fn event_scope<'a, S>(
+    event: &tracing::Event<'_>,
+    ctx: &'a tracing_subscriber::layer::Context<'_, S>,
+) -> impl Iterator<Item = tracing_subscriber::registry::SpanRef<'a, S>>
 where
-    R: tracing_subscriber::registry::LookupSpan<'a>,
+    S: Subscriber,
+    S: for<'lookup> tracing_subscriber::registry::LookupSpan<'lookup>,
 {
-    fn new(v: Scope<'a, R>) -> Self {
-        ScopeSerializer(Cell::new(Some(v)))
-    }
+    ctx.event_scope(event)
+        .into_iter()
+        .flat_map(|scope| scope.from_root())
+}
+
+/// What `with_spans` noticed about the fields of the spans it borrowed.
+struct SpanNotes {
+    warnings: Vec<String>,
+    /// The integers the numeric policy wrote as strings in any of them.
+    stringified: Stringified,
 }
 
-impl<'a, R> serde::Serialize for ScopeSerializer<'a, R>
+/// Borrow the data of spans, in the order they're given, and hand it to `f` along with notes about
+/// their fields. The spans' extensions stay locked until `f` returns, so `f` mustn't touch them.
+fn with_spans<'a, R, T>(
+    scope: impl Iterator<Item = tracing_subscriber::registry::SpanRef<'a, R>>,
+    f: impl FnOnce(&[SpanView<'_>], SpanNotes) -> T,
+) -> T
 where
-    R: tracing_subscriber::registry::LookupSpan<'a>,
+    R: tracing_subscriber::registry::LookupSpan<'a> + 'a,
 {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut seq = serializer.serialize_seq(None)?;
-        let scope = self.0.replace(None);
-        if let Some(scope) = scope {
-            for span in scope.from_root() {
-                let extensions = span.extensions();
-                if let Some(data) = extensions.get::<CustomLayerTracedData>() {
-                    seq.serialize_element(data)?;
-                }
-            }
+    let scope: Vec<_> = scope.collect();
+    let extensions: Vec<_> = scope.iter().map(|span| span.extensions()).collect();
+    let mut warnings = Vec::new();
+    let mut stringified = Stringified::new();
+    let mut spans = Vec::with_capacity(scope.len());
+    for (span, extensions) in scope.iter().zip(&extensions) {
+        let Some(data) = extensions.get::<CustomLayerTracedData>() else {
+            continue;
+        };
+        if let Some(metadata) = &data.metadata {
+            let prefix = format!("spans.{}", metadata.metadata.name());
+            warnings.extend(data.non_finite_warnings(&prefix));
+            stringified.extend(data.stringified.iter().cloned());
+            spans.push(SpanView {
+                id: span.id(),
+                metadata,
+                fields: &data.fields,
+                unset: &data.unset,
+            });
         }
-        seq.end()
+    }
+    f(
+        &spans,
+        SpanNotes {
+            warnings,
+            stringified,
+        },
+    )
+}
+
+/// A stable index for each level, for per-level configuration.
+fn level_index(level: &Level) -> usize {
+    match *level {
+        Level::TRACE => 0,
+        Level::DEBUG => 1,
+        Level::INFO => 2,
+        Level::WARN => 3,
+        Level::ERROR => 4,
     }
 }
 
@@ -357,3 +1910,256 @@ fn format_level(level: &Level) -> &'static str {
         Level::WARN => "WARN",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::testing::capture;
+    use super::*;
+
+    fn log_in_spans() {
+        let outer = tracing::info_span!("request", path = "/", user = tracing::field::Empty);
+        let _outer = outer.enter();
+        let inner = tracing::info_span!("load", id = 7);
+        let _inner = inner.enter();
+        tracing::info!(count = 3, "loaded");
+    }
+
+    /// Without anything that needs an `EventData`, events are written from borrowed data. A
+    /// processor that does nothing should make no difference to what's written.
+    #[test]
+    fn borrowed_and_owned_events_are_written_the_same() {
+        let configure = || {
+            CustomJsonLayer::default()
+                .with_span_context(SpanContext::Object)
+                .with_unset_fields(UnsetFields::List)
+                .with_static_field("service", "api")
+        };
+        let borrowed = capture(configure(), log_in_spans).json();
+        let owned = capture(
+            configure().with_processor(|_: &mut EventData| Processed::Keep),
+            log_in_spans,
+        )
+        .json();
+
+        assert_eq!(borrowed.len(), 1);
+        let without_timestamp = |mut line: serde_json::Value| {
+            line.as_object_mut().unwrap().remove("timestamp");
+            line
+        };
+        assert_eq!(
+            without_timestamp(borrowed[0].clone()),
+            without_timestamp(owned[0].clone())
+        );
+        assert_eq!(borrowed[0]["context"]["id"], 7);
+        assert_eq!(borrowed[0]["spans"][0]["unset_fields"], json!(["user"]));
+    }
+
+    #[test]
+    fn parses_only_debug_output_with_some_structure() {
+        #[allow(dead_code)]
+        #[derive(Debug)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        let layer = CustomJsonLayer::default().with_debug_parsing(true);
+        let captured = capture(layer, || {
+            tracing::info!(
+                point = ?Point { x: 1, y: 2 },
+                list = ?[1, 2],
+                zip = %"00501",
+                name = %"None",
+                flag = %"true",
+                retry = ?Some(3),
+            );
+        });
+        assert_eq!(
+            captured.json()[0]["fields"],
+            json!({
+                "point": { "x": 1, "y": 2 },
+                "list": [1, 2],
+                "zip": "00501",
+                "name": "None",
+                "flag": "true",
+                "retry": "Some(3)",
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_recorded_span_fields_by_policy() {
+        let record_twice = || {
+            let span = tracing::info_span!("load", state = "loading");
+            let _span = span.enter();
+            span.record("state", "loaded");
+            tracing::info!("loaded");
+        };
+        for (policy, state) in [
+            (RepeatedFieldPolicy::LastWins, json!("loaded")),
+            (RepeatedFieldPolicy::FirstWins, json!("loading")),
+            (RepeatedFieldPolicy::Collect, json!(["loading", "loaded"])),
+        ] {
+            let layer = CustomJsonLayer::default().with_repeated_fields(policy);
+            let line = &capture(layer, record_twice).json()[0];
+            assert_eq!(line["span"]["state"], state, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn merges_span_fields_with_the_innermost_winning() {
+        let log = || {
+            let outer = tracing::info_span!("request", user = 1, id = "outer", secret = "s");
+            let _outer = outer.enter();
+            let inner = tracing::info_span!("load", id = "inner");
+            let _inner = inner.enter();
+            tracing::info!(count = 3, user = 2, "loaded");
+        };
+        let fields = json!({ "count": 3, "user": 2 });
+        for (span_context, context, fields) in [
+            (SpanContext::None, None, fields.clone()),
+            (
+                SpanContext::Object,
+                Some(json!({ "user": 1, "id": "inner" })),
+                fields,
+            ),
+            (
+                SpanContext::Fields,
+                None,
+                json!({ "count": 3, "user": 2, "id": "inner" }),
+            ),
+        ] {
+            // Written from borrowed span data, and from an `EventData`.
+            for owned in [false, true] {
+                let mut layer = CustomJsonLayer::default()
+                    .with_span_context(span_context)
+                    .with_uninherited_fields(["secret"]);
+                if owned {
+                    layer = layer.with_processor(|_: &mut EventData| Processed::Keep);
+                }
+                let line = &capture(layer, log).json()[0];
+                assert_eq!(line.get("context"), context.as_ref(), "{:?}", span_context);
+                assert_eq!(line["fields"], fields, "{:?}", span_context);
+            }
+        }
+    }
+
+    #[test]
+    fn writes_the_spans_each_output_mode_asks_for() {
+        let span_keys = |line: &serde_json::Value| -> Vec<String> {
+            ["span", "spans", "root_span", "span_ids"]
+                .into_iter()
+                .filter(|key| line.get(key).is_some())
+                .map(String::from)
+                .collect()
+        };
+        for (span_output, keys) in [
+            (SpanOutput::LeafAndAll, &["span", "spans"][..]),
+            (SpanOutput::Leaf, &["span"]),
+            (SpanOutput::Root, &["root_span"]),
+            (SpanOutput::All, &["spans"]),
+            (SpanOutput::Ids, &["span_ids"]),
+            (SpanOutput::Merged, &["span"]),
+            (SpanOutput::None, &[]),
+        ] {
+            let layer = CustomJsonLayer::default().with_span_output(span_output);
+            let line = &capture(layer, log_in_spans).json()[0];
+            assert_eq!(span_keys(line), keys, "{:?}", span_output);
+
+            match span_output {
+                SpanOutput::LeafAndAll | SpanOutput::Leaf => {
+                    assert_eq!(
+                        line["span"],
+                        json!({ "target": module_path!(), "name": "load", "id": 7 })
+                    );
+                }
+                SpanOutput::Root => {
+                    assert_eq!(line["root_span"]["name"], "request");
+                    assert_eq!(line["root_span"]["path"], "/");
+                }
+                SpanOutput::All => {
+                    let names: Vec<_> = line["spans"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|span| &span["name"])
+                        .collect();
+                    assert_eq!(names, ["request", "load"]);
+                }
+                SpanOutput::Ids => assert_eq!(line["span_ids"].as_array().unwrap().len(), 2),
+                SpanOutput::Merged => {
+                    assert_eq!(
+                        line["span"],
+                        json!({ "target": module_path!(), "name": "load", "path": "/", "id": 7 })
+                    );
+                }
+                SpanOutput::None => {}
+            }
+        }
+    }
+
+    #[test]
+    fn writes_spans_by_level() {
+        let layer = CustomJsonLayer::default()
+            .with_span_output(SpanOutput::None)
+            .with_span_output_for(Level::ERROR, SpanOutput::Root);
+        let captured = capture(layer, || {
+            let _span = tracing::info_span!("request").entered();
+            tracing::info!("fine");
+            tracing::error!("broken");
+        });
+        let lines = captured.json();
+        assert_eq!(lines[0].get("root_span"), None);
+        assert_eq!(lines[1]["root_span"]["name"], "request");
+    }
+
+    #[test]
+    fn writes_unset_span_fields_by_policy() {
+        let log = || {
+            let span = tracing::info_span!(
+                "request",
+                path = "/",
+                user = tracing::field::Empty,
+                status = tracing::field::Empty
+            );
+            let _span = span.enter();
+            tracing::info!("started");
+            span.record("user", "ana");
+            tracing::info!("authenticated");
+        };
+        let request = |fields: serde_json::Value| {
+            let mut span = json!({ "target": module_path!(), "name": "request" });
+            span.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            span
+        };
+        for (unset_fields, started, authenticated) in [
+            (
+                UnsetFields::Omit,
+                json!({ "path": "/" }),
+                json!({ "path": "/", "user": "ana" }),
+            ),
+            (
+                UnsetFields::Null,
+                json!({ "path": "/", "user": null, "status": null }),
+                json!({ "path": "/", "user": "ana", "status": null }),
+            ),
+            (
+                UnsetFields::List,
+                json!({ "path": "/", "unset_fields": ["user", "status"] }),
+                json!({ "path": "/", "user": "ana", "unset_fields": ["status"] }),
+            ),
+        ] {
+            let layer = CustomJsonLayer::default().with_unset_fields(unset_fields);
+            let lines = capture(layer, log).json();
+            assert_eq!(lines[0]["span"], request(started), "{:?}", unset_fields);
+            assert_eq!(
+                lines[1]["span"],
+                request(authenticated),
+                "{:?}",
+                unset_fields
+            );
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Write byte slices as strings, instead of as their `Debug` output.
+
+/// How to write byte slices recorded on events and spans.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BytesEncoding {
+    /// Standard base64, with padding.
+    #[default]
+    Base64,
+    /// Lowercase hex.
+    Hex,
+    /// As text, with invalid UTF-8 replaced by `U+FFFD`.
+    Utf8Lossy,
+}
+
+impl BytesEncoding {
+    pub(crate) fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            BytesEncoding::Base64 => base64(bytes),
+            BytesEncoding::Hex => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            BytesEncoding::Utf8Lossy => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BytesEncoding;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    #[test]
+    fn encodes_the_rfc_4648_base64_test_vectors() {
+        // Padded with nothing, two `=`s, and one `=`.
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (bytes, encoded) in vectors {
+            assert_eq!(BytesEncoding::Base64.encode(bytes.as_bytes()), encoded);
+        }
+        assert_eq!(BytesEncoding::Base64.encode(&[0xfb, 0xff, 0xbf]), "+/+/");
+    }
+
+    #[test]
+    fn encodes_hex_and_utf8() {
+        assert_eq!(BytesEncoding::Hex.encode(b""), "");
+        assert_eq!(
+            BytesEncoding::Hex.encode(&[0x00, 0x0f, 0xab, 0xff]),
+            "000fabff"
+        );
+        assert_eq!(BytesEncoding::Utf8Lossy.encode(b"ok\xff"), "ok\u{fffd}");
+    }
+
+    #[test]
+    fn writes_recorded_bytes() {
+        let layer = CustomJsonLayer::default().with_bytes_encoding(BytesEncoding::Utf8Lossy);
+        let captured = capture(layer, || {
+            tracing::info!(bytes = &b"tracing\xff"[..], "hi");
+        });
+        assert_eq!(captured.json()[0]["fields"]["bytes"], "tracing\u{fffd}");
+    }
+}
@@ -0,0 +1,118 @@
+//! Pre-escaped JSON for the parts of a callsite that never change.
+//!
+//! The level, target, and name of an event or span are `'static` and known as soon as the callsite
+//! is registered, so there's no reason to escape and serialize them again for every single line we
+//! write. Instead we serialize them once, keep the bytes around as `RawValue`s, and have
+//! `serde_json` copy them straight into the output.
+
+use serde_json::value::{to_raw_value, RawValue};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+use tracing::{callsite::Identifier, Metadata};
+
+thread_local! {
+    /// Each thread's copy of the fragments it has used, so that looking them up for an event
+    /// doesn't take the shared lock. The fragments only depend on the callsite, so every cache
+    /// shares these, and there's at most one entry per callsite however many layers come and go.
+    static LOCAL: RefCell<HashMap<Identifier, Arc<CallsiteFragments>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// The serialized JSON values for a single callsite.
+pub(crate) struct CallsiteFragments {
+    pub level: Box<RawValue>,
+    pub target: Box<RawValue>,
+    pub name: Box<RawValue>,
+}
+
+impl CallsiteFragments {
+    fn new(metadata: &Metadata<'_>) -> Result<Self, serde_json::Error> {
+        Ok(CallsiteFragments {
+            level: to_raw_value(super::format_level(metadata.level()))?,
+            target: to_raw_value(metadata.target())?,
+            name: to_raw_value(metadata.name())?,
+        })
+    }
+}
+
+/// All of the callsites that the layer has seen, keyed by their identifier.
+#[derive(Default)]
+pub(crate) struct CallsiteCache {
+    shared: RwLock<HashMap<Identifier, Arc<CallsiteFragments>>>,
+}
+
+impl CallsiteCache {
+    /// Serialize the fragments for a newly registered callsite.
+    pub fn register(&self, metadata: &'static Metadata<'static>) {
+        let _ = self.shared_get(metadata);
+    }
+
+    /// Get the fragments for a callsite. This is called for every event and span, so it looks in
+    /// the thread's own cache first, and only takes the shared lock the first time a thread sees a
+    /// callsite.
+    pub fn get(
+        &self,
+        metadata: &'static Metadata<'static>,
+    ) -> Result<Arc<CallsiteFragments>, serde_json::Error> {
+        let key = metadata.callsite();
+        let local = LOCAL.try_with(|local| local.borrow().get(&key).cloned());
+        if let Ok(Some(fragments)) = local {
+            return Ok(fragments);
+        }
+
+        let fragments = self.shared_get(metadata)?;
+        let _ = LOCAL.try_with(|local| {
+            local.borrow_mut().insert(key, Arc::clone(&fragments));
+        });
+        Ok(fragments)
+    }
+
+    /// Get the fragments from the shared cache, serializing them first if `register` was never
+    /// called for the callsite (which can happen if it was registered before this layer existed).
+    fn shared_get(
+        &self,
+        metadata: &'static Metadata<'static>,
+    ) -> Result<Arc<CallsiteFragments>, serde_json::Error> {
+        let id = metadata.callsite();
+        if let Ok(callsites) = self.shared.read() {
+            if let Some(fragments) = callsites.get(&id) {
+                return Ok(Arc::clone(fragments));
+            }
+        }
+
+        let fragments = Arc::new(CallsiteFragments::new(metadata)?);
+        if let Ok(mut callsites) = self.shared.write() {
+            callsites.insert(id, Arc::clone(&fragments));
+        }
+        Ok(fragments)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::Callsite;
+
+    use super::{CallsiteCache, LOCAL};
+
+    #[test]
+    fn keeps_one_thread_local_entry_per_callsite() {
+        let metadata = tracing::callsite! {
+            name: "cached",
+            kind: tracing::metadata::Kind::EVENT,
+            level: tracing::Level::INFO,
+            fields: []
+        }
+        .metadata();
+
+        for _ in 0..100 {
+            let cache = CallsiteCache::default();
+            let fragments = cache.get(metadata).unwrap();
+            assert_eq!(fragments.name.get(), r#""cached""#);
+            assert_eq!(fragments.level.get(), r#""INFO""#);
+        }
+        assert_eq!(LOCAL.with(|local| local.borrow().len()), 1);
+    }
+}
@@ -0,0 +1,255 @@
+//! Canonical log lines: one wide line per span, written when it closes, with everything recorded
+//! on the span and a summary of the events inside it. Often that one line is all that's needed to
+//! answer a question about a request, without joining its events back together.
+//!
+//! ```no_compile
+//! let layer = CustomJsonLayer::default().with_canonical_lines(
+//!     CanonicalLines::new()
+//!         .for_name("request")
+//!         .for_target("my_app::jobs")
+//!         .with_counter("db_queries"),
+//! );
+//! ```
+//!
+//! The line goes through the pipeline like any other event, with the span's level and target,
+//! the span's name as its message, and its fields as `fields`. The events inside the span are
+//! summarized as they're written, so the ones a processor drops aren't counted, and `last_error`
+//! has the fields of the last error after processors and key renames have been at them:
+//!
+//! ```json
+//! {
+//!   "level": "INFO",
+//!   "message": "request",
+//!   "target": "my_app",
+//!   "canonical": true,
+//!   "duration_ms": 12.5,
+//!   "event_counts": { "ERROR": 1, "INFO": 3 },
+//!   "last_error": { "fields": { "attempt": 2 }, "message": "timed out" },
+//!   "counters": { "db_queries": 4 },
+//!   "fields": { "path": "/" },
+//!   ...
+//! }
+//! ```
+
+use indexmap::IndexMap;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::time::Instant;
+use tracing::{Level, Metadata};
+
+use super::keys::KeyTransform;
+use super::{format_level, level_index, EventData, Fields};
+
+/// Which spans get a canonical line, and what's collected from their events.
+#[derive(Default)]
+pub struct CanonicalLines {
+    names: HashSet<String>,
+    targets: Vec<String>,
+    counters: Vec<String>,
+}
+
+/// What's been collected for a span so far. Kept in the span's extensions.
+pub(crate) struct CanonicalLine {
+    started: Instant,
+    /// Indexed by `level_index`.
+    event_counts: [u64; 5],
+    last_error: Option<Value>,
+    counters: IndexMap<String, Value>,
+}
+
+impl CanonicalLines {
+    /// Write a canonical line for every span, unless `for_name` or `for_target` narrow it down.
+    pub fn new() -> Self {
+        CanonicalLines::default()
+    }
+
+    /// Write a canonical line for spans with this name.
+    pub fn for_name(mut self, name: impl Into<String>) -> Self {
+        self.names.insert(name.into());
+        self
+    }
+
+    /// Write a canonical line for spans whose target starts with this (like `my_app::jobs`).
+    pub fn for_target(mut self, target: impl Into<String>) -> Self {
+        self.targets.push(target.into());
+        self
+    }
+
+    /// Add up a field across the events inside the span, into `counters`. Numbers are added as
+    /// they are, and any other value counts as 1.
+    pub fn with_counter(mut self, field: impl Into<String>) -> Self {
+        self.counters.push(field.into());
+        self
+    }
+
+    pub(crate) fn selects(&self, metadata: &Metadata<'_>) -> bool {
+        if self.names.is_empty() && self.targets.is_empty() {
+            return true;
+        }
+        self.names.contains(metadata.name())
+            || self
+                .targets
+                .iter()
+                .any(|target| metadata.target().starts_with(target.as_str()))
+    }
+
+    pub(crate) fn start(&self) -> CanonicalLine {
+        CanonicalLine {
+            started: Instant::now(),
+            event_counts: [0; 5],
+            last_error: None,
+            counters: IndexMap::new(),
+        }
+    }
+
+    /// Collect what's needed from an event inside the span, once the pipeline is done with it and
+    /// `keys` has renamed its fields.
+    pub(crate) fn observe(&self, line: &mut CanonicalLine, event: &EventData, keys: &KeyTransform) {
+        let level = event.metadata().level();
+        line.event_counts[level_index(level)] += 1;
+        if *level == Level::ERROR {
+            line.last_error = Some(json!({
+                "message": event.message,
+                "fields": event.fields,
+            }));
+        }
+
+        for name in &self.counters {
+            let Some(value) = event.fields.get(keys.field_name(name).as_ref()) else {
+                continue;
+            };
+            let count = line.counters.entry(name.clone()).or_insert(json!(0));
+            *count = add(count, value);
+        }
+    }
+
+    /// The top-level keys of the span's canonical line.
+    pub(crate) fn finish(&self, line: CanonicalLine) -> Fields {
+        let event_counts: serde_json::Map<String, Value> = [
+            Level::TRACE,
+            Level::DEBUG,
+            Level::INFO,
+            Level::WARN,
+            Level::ERROR,
+        ]
+        .iter()
+        .filter(|level| line.event_counts[level_index(level)] > 0)
+        .map(|level| {
+            let count = line.event_counts[level_index(level)];
+            (format_level(level).to_owned(), json!(count))
+        })
+        .collect();
+
+        let mut extra = Fields::new();
+        extra.insert("canonical".into(), json!(true));
+        extra.insert(
+            "duration_ms".into(),
+            json!(line.started.elapsed().as_secs_f64() * 1000.0),
+        );
+        extra.insert("event_counts".into(), event_counts.into());
+        if let Some(last_error) = line.last_error {
+            extra.insert("last_error".into(), last_error);
+        }
+        if !self.counters.is_empty() {
+            // Counters that never showed up are written as 0, so they're always there to query.
+            let counters: serde_json::Map<String, Value> = self
+                .counters
+                .iter()
+                .map(|name| {
+                    let count = line.counters.get(name).cloned().unwrap_or(json!(0));
+                    (name.clone(), count)
+                })
+                .collect();
+            extra.insert("counters".into(), counters.into());
+        }
+        extra
+    }
+}
+
+/// Add a field's value to a counter, staying an integer for as long as possible.
+fn add(count: &Value, value: &Value) -> Value {
+    let increment = if value.is_number() { value } else { &json!(1) };
+    match count
+        .as_i64()
+        .zip(increment.as_i64())
+        .and_then(|(a, b)| a.checked_add(b))
+    {
+        Some(sum) => json!(sum),
+        None => json!(count.as_f64().unwrap_or(0.0) + increment.as_f64().unwrap_or(0.0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tracing::Level;
+
+    use super::CanonicalLines;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::{CustomJsonLayer, EventData, KeyCase, KeyDepth, Processed};
+
+    fn layer() -> CustomJsonLayer {
+        CustomJsonLayer::default().with_canonical_lines(
+            CanonicalLines::new()
+                .for_name("request")
+                .with_counter("db_queries"),
+        )
+    }
+
+    fn handle_request() {
+        let span = tracing::info_span!("request", path = "/");
+        let _span = span.enter();
+        tracing::info!(db_queries = 2, "loaded");
+        tracing::info!(db_queries = 1, "saved");
+        tracing::error!(retry_count = 2, password = "hunter2", "timed out");
+        tracing::info_span!("render").in_scope(|| tracing::debug!("rendered"));
+    }
+
+    #[test]
+    fn writes_a_line_when_the_span_closes() {
+        let lines = capture(layer(), handle_request).json();
+
+        // Only `request` gets a line, and it comes after every event inside it.
+        assert_eq!(lines.len(), 5);
+        let line = &lines[4];
+        assert_eq!(line["message"], "request");
+        assert_eq!(line["canonical"], true);
+        assert!(line["duration_ms"].is_f64());
+        assert_eq!(line["fields"], json!({ "path": "/" }));
+        assert_eq!(
+            line["event_counts"],
+            json!({ "DEBUG": 1, "INFO": 2, "ERROR": 1 })
+        );
+        assert_eq!(line["counters"], json!({ "db_queries": 3 }));
+        assert_eq!(
+            line["last_error"],
+            json!({
+                "message": "timed out",
+                "fields": { "retry_count": 2, "password": "hunter2" },
+            })
+        );
+    }
+
+    #[test]
+    fn summarizes_events_as_they_are_written() {
+        let layer = layer()
+            .with_processor(|event: &mut EventData| {
+                if *event.metadata().level() == Level::DEBUG {
+                    return Processed::Drop;
+                }
+                event.fields.shift_remove("password");
+                Processed::Keep
+            })
+            .with_key_case(KeyCase::Camel, KeyDepth::Fields);
+        let lines = capture(layer, handle_request).json();
+
+        let line = &lines[3];
+        assert_eq!(line["canonical"], true);
+        assert_eq!(line["eventCounts"], json!({ "INFO": 2, "ERROR": 1 }));
+        assert_eq!(line["counters"], json!({ "db_queries": 3 }));
+        assert_eq!(
+            line["lastError"],
+            json!({ "message": "timed out", "fields": { "retryCount": 2 } })
+        );
+    }
+}
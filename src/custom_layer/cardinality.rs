@@ -0,0 +1,195 @@
+//! Guard against maps with unbounded dynamic keys, like a `BTreeMap` keyed by user IDs, which
+//! would otherwise add a new field to the log store's mapping for every key.
+//!
+//! The guard keeps track of the distinct keys it has seen for each object in the output, by path
+//! (like `fields.json_new.the_rest`). Once an object has had more than the limit, it's written in a
+//! way that doesn't create new mappings from then on.
+
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::Fields;
+
+/// How to write a map once it has had too many distinct keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CardinalityFallback {
+    /// As an array of `[key, value]` pairs.
+    #[default]
+    Pairs,
+    /// As a string containing the map's JSON.
+    String,
+}
+
+enum KeysSeen {
+    Tracking(HashSet<String>),
+    Exceeded,
+}
+
+pub(crate) struct CardinalityGuard {
+    limit: usize,
+    fallback: CardinalityFallback,
+    seen: Mutex<HashMap<String, KeysSeen>>,
+}
+
+impl CardinalityGuard {
+    pub fn new(limit: usize, fallback: CardinalityFallback) -> Self {
+        CardinalityGuard {
+            limit,
+            fallback,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check every object inside the values of `fields`, whose paths start with `prefix`. The first
+    /// time an object goes over the limit, a warning is added to `warnings`.
+    pub fn guard(&self, prefix: &str, fields: &mut Fields, warnings: &mut Vec<String>) {
+        let mut seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for (key, value) in fields {
+            let path = format!("{}.{}", prefix, key);
+            self.guard_value(&mut seen, path, value, warnings);
+        }
+    }
+
+    fn guard_value(
+        &self,
+        seen: &mut HashMap<String, KeysSeen>,
+        path: String,
+        value: &mut Value,
+        warnings: &mut Vec<String>,
+    ) {
+        match value {
+            Value::Object(object) => {
+                let keys = seen
+                    .entry(path.clone())
+                    .or_insert_with(|| KeysSeen::Tracking(HashSet::new()));
+                if let KeysSeen::Tracking(keys_seen) = keys {
+                    for key in object.keys() {
+                        if !keys_seen.contains(key) {
+                            keys_seen.insert(key.clone());
+                        }
+                    }
+                    if keys_seen.len() > self.limit {
+                        *keys = KeysSeen::Exceeded;
+                        warnings.push(format!(
+                            "{} has had more than {} distinct keys, so it's no longer written as an object",
+                            path, self.limit
+                        ));
+                    }
+                }
+
+                if let KeysSeen::Exceeded = keys {
+                    *value = self.fallback(std::mem::take(object));
+                    return;
+                }
+
+                for (key, value) in object {
+                    let path = format!("{}.{}", path, key);
+                    self.guard_value(seen, path, value, warnings);
+                }
+            }
+            Value::Array(array) => {
+                let path = format!("{}[]", path);
+                for value in array {
+                    self.guard_value(seen, path.clone(), value, warnings);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn fallback(&self, object: serde_json::Map<String, Value>) -> Value {
+        match self.fallback {
+            CardinalityFallback::Pairs => object
+                .into_iter()
+                .map(|(key, value)| Value::Array(vec![Value::String(key), value]))
+                .collect(),
+            CardinalityFallback::String => Value::Object(object).to_string().into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::CardinalityFallback;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+    use crate::macros::tracing_json_old_helper;
+
+    fn log_growing_maps(fallback: CardinalityFallback) -> Vec<serde_json::Value> {
+        let layer = CustomJsonLayer::default().with_cardinality_limit(2, fallback);
+        let captured = capture(layer, || {
+            for key in ["a", "b", "c", "d"] {
+                let counts = tracing_json_old_helper(&json!({ key: 1, "nested": { key: 2 } }));
+                tracing::info!(counts = counts.as_str(), "counted");
+            }
+        });
+        captured.json()
+    }
+
+    #[test]
+    fn writes_maps_over_the_limit_as_pairs() {
+        let lines = log_growing_maps(CardinalityFallback::Pairs);
+        assert_eq!(
+            lines[0]["fields"]["counts"],
+            json!({ "a": 1, "nested": { "a": 2 } })
+        );
+        // `counts` has had `a`, `nested`, and `b`, but `nested` has only had two keys so far.
+        assert_eq!(
+            lines[1]["fields"]["counts"],
+            json!([["b", 1], ["nested", { "b": 2 }]])
+        );
+        assert_eq!(
+            lines[2]["fields"]["counts"],
+            json!([["c", 1], ["nested", { "c": 2 }]])
+        );
+        assert_eq!(
+            lines[3]["fields"]["counts"],
+            json!([["d", 1], ["nested", { "d": 2 }]])
+        );
+    }
+
+    #[test]
+    fn writes_maps_over_the_limit_as_strings() {
+        let lines = log_growing_maps(CardinalityFallback::String);
+        assert_eq!(
+            lines[0]["fields"]["counts"],
+            json!({ "a": 1, "nested": { "a": 2 } })
+        );
+        assert_eq!(lines[1]["fields"]["counts"], r#"{"b":1,"nested":{"b":2}}"#);
+    }
+
+    #[test]
+    fn warns_once_per_path() {
+        let layer =
+            CustomJsonLayer::default().with_cardinality_limit(2, CardinalityFallback::Pairs);
+        let captured = capture(layer, || {
+            for key in ["a", "b", "c", "d"] {
+                let map = tracing_json_old_helper(&json!({ key: 1 }));
+                tracing::info!(counts = map.as_str(), sizes = map.as_str(), "counted");
+            }
+        });
+        let warnings: Vec<_> = captured
+            .json()
+            .iter()
+            .map(|line| line.get("warnings").cloned())
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                None,
+                None,
+                Some(json!([
+                    "fields.counts has had more than 2 distinct keys, so it's no longer written as an object",
+                    "fields.sizes has had more than 2 distinct keys, so it's no longer written as an object",
+                ])),
+                None,
+            ]
+        );
+    }
+}
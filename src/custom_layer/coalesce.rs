@@ -0,0 +1,298 @@
+//! Coalesce repeated events: when a callsite logs the same thing over and over (like a warning
+//! during an incident), write the first one, and then a single summary of the repeats instead of
+//! every one of them.
+//!
+//! Events are repeats of each other if they come from the same callsite, one after the other on the
+//! same thread, and have the same message, fields, and span fields once the pipeline is done with
+//! them. Only their timestamps can differ. Each thread has its own run, so events on other threads
+//! don't end it. A run of repeats ends when anything else is logged on its thread, when the window
+//! has passed since the first event of the run and any thread logs something, or when the layer is
+//! dropped, and its summary is written then. The summary is a copy of the first event with the time
+//! of the last repeat, and a few extra keys:
+//!
+//! ```json
+//! {
+//!   "timestamp": "2021-04-21T01:02:04.900000000Z",
+//!   "level": "WARN",
+//!   "message": "connection refused",
+//!   "repeat_count": 4123,
+//!   "first_timestamp": "2021-04-21T01:02:03.000000000Z",
+//!   "last_timestamp": "2021-04-21T01:02:04.900000000Z",
+//!   ...
+//! }
+//! ```
+//!
+//! `repeat_count` is how many events weren't written, and the timestamps are those of the first
+//! event (the one that was written) and the last repeat.
+//!
+//! The spans the first event was in may well have closed by the time the run ends, so the summary
+//! is written outside of any span: it keeps their fields, but isn't held back by tail sampling or
+//! put in a span's flight recorder buffer.
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+
+use super::EventData;
+
+pub(crate) struct Coalescer {
+    window: Duration,
+    /// Each thread's run, which the last event it logged started or was part of.
+    runs: Mutex<HashMap<ThreadId, Run>>,
+}
+
+/// An event and its repeats.
+struct Run {
+    first: EventData,
+    started: Instant,
+    repeat_count: u64,
+    last_timestamp: DateTime<Utc>,
+}
+
+/// What to do with an event.
+pub(crate) enum Coalesced {
+    /// It's a repeat, so don't write it.
+    Repeat,
+    /// Write it, after the summaries of the runs that ended, for those that had any repeats.
+    Write(Vec<EventData>),
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Coalescer {
+            window,
+            runs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn coalesce(&self, event: &EventData) -> Coalesced {
+        let mut runs = self.lock();
+        let now = Instant::now();
+        let thread = std::thread::current().id();
+
+        if let Some(run) = runs.get_mut(&thread) {
+            if run.first.metadata().callsite() == event.metadata().callsite()
+                && now.duration_since(run.started) <= self.window
+                && is_repeat(&run.first, event)
+            {
+                run.repeat_count += 1;
+                run.last_timestamp = event.timestamp;
+                return Coalesced::Repeat;
+            }
+        }
+
+        // This event starts a new run, so this thread's last one has ended, along with any other
+        // thread's whose window has passed (that thread might have gone quiet, or exited).
+        let mut ended: Vec<Run> = runs.remove(&thread).into_iter().collect();
+        let expired: Vec<ThreadId> = runs
+            .iter()
+            .filter(|(_, run)| now.duration_since(run.started) > self.window)
+            .map(|(&thread, _)| thread)
+            .collect();
+        ended.extend(expired.iter().filter_map(|thread| runs.remove(thread)));
+        runs.insert(
+            thread,
+            Run {
+                first: event.clone(),
+                started: now,
+                repeat_count: 0,
+                last_timestamp: event.timestamp,
+            },
+        );
+        Coalesced::Write(ended.into_iter().filter_map(summarize).collect())
+    }
+
+    /// End every run, returning the summaries of those that had any repeats.
+    pub fn finish(&self) -> Vec<EventData> {
+        self.lock()
+            .drain()
+            .filter_map(|(_, run)| summarize(run))
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<ThreadId, Run>> {
+        match self.runs.lock() {
+            Ok(run) => run,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+fn is_repeat(first: &EventData, event: &EventData) -> bool {
+    first.message == event.message
+        && first.fields == event.fields
+        && first.spans.len() == event.spans.len()
+        && first
+            .spans
+            .iter()
+            .zip(&event.spans)
+            .all(|(a, b)| a.fields == b.fields)
+}
+
+fn summarize(run: Run) -> Option<EventData> {
+    if run.repeat_count == 0 {
+        return None;
+    }
+    let mut summary = run.first.clone();
+    summary.timestamp = run.last_timestamp;
+    summary
+        .extra
+        .insert("repeat_count".into(), json!(run.repeat_count));
+    summary
+        .extra
+        .insert("first_timestamp".into(), json!(run.first.timestamp));
+    summary
+        .extra
+        .insert("last_timestamp".into(), json!(run.last_timestamp));
+    Some(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::custom_layer::tail::TailSampling;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    fn layer() -> CustomJsonLayer {
+        CustomJsonLayer::default().with_repeat_coalescing(Duration::from_secs(60))
+    }
+
+    #[test]
+    fn writes_a_run_that_is_still_going_at_shutdown() {
+        let captured = capture(layer(), || {
+            for _ in 0..5 {
+                tracing::warn!("same");
+            }
+        });
+        let lines = captured.json();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["message"], "same");
+        assert!(lines[0].get("repeat_count").is_none());
+        assert_eq!(lines[1]["message"], "same");
+        assert_eq!(lines[1]["repeat_count"], 4);
+        assert_eq!(lines[1]["first_timestamp"], lines[0]["timestamp"]);
+        assert_eq!(lines[1]["last_timestamp"], lines[1]["timestamp"]);
+    }
+
+    #[test]
+    fn ends_a_run_when_another_callsite_logs() {
+        let captured = capture(layer(), || {
+            for _ in 0..3 {
+                tracing::warn!("same");
+            }
+            tracing::info!("other");
+        });
+        let lines = captured.json();
+
+        let messages: Vec<_> = lines.iter().map(|line| &line["message"]).collect();
+        assert_eq!(messages, ["same", "same", "other"]);
+        assert_eq!(lines[1]["repeat_count"], 2);
+    }
+
+    #[test]
+    fn ends_a_run_when_the_event_changes() {
+        let captured = capture(layer(), || {
+            for attempt in [1, 1, 2] {
+                tracing::warn!(attempt, "retrying");
+            }
+        });
+        let attempts: Vec<_> = captured
+            .json()
+            .iter()
+            .map(|line| line["fields"]["attempt"].clone())
+            .collect();
+        assert_eq!(attempts, [1, 1, 2]);
+    }
+
+    #[test]
+    fn keeps_a_run_for_each_thread() {
+        let captured = capture(layer(), || {
+            for i in 0..3 {
+                tracing::warn!("main");
+                // Log the other thread's events in the middle of this thread's run.
+                if i == 0 {
+                    let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+                    std::thread::spawn(move || {
+                        tracing::dispatcher::with_default(&dispatch, || {
+                            for _ in 0..3 {
+                                tracing::warn!("other");
+                            }
+                        })
+                    })
+                    .join()
+                    .unwrap();
+                }
+            }
+        });
+        let lines = captured.json();
+
+        // The other thread's events don't end the main thread's run, and vice versa, so both
+        // summaries are only written when the layer is dropped.
+        let messages: Vec<_> = lines.iter().map(|line| &line["message"]).collect();
+        assert_eq!(messages[..2], ["main", "other"]);
+        let mut summaries: Vec<_> = lines[2..]
+            .iter()
+            .map(|line| (line["message"].clone(), line["repeat_count"].clone()))
+            .collect();
+        summaries.sort_by_key(|(message, _)| message.to_string());
+        assert_eq!(
+            summaries,
+            [("main".into(), 2.into()), ("other".into(), 2.into())]
+        );
+    }
+
+    #[test]
+    fn ends_other_threads_runs_once_their_window_has_passed() {
+        let layer = CustomJsonLayer::default().with_repeat_coalescing(Duration::from_millis(10));
+        let captured = capture(layer, || {
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            std::thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    for _ in 0..2 {
+                        tracing::warn!("other");
+                    }
+                })
+            })
+            .join()
+            .unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            tracing::warn!("main");
+        });
+        let lines = captured.json();
+
+        let messages: Vec<_> = lines.iter().map(|line| &line["message"]).collect();
+        assert_eq!(messages, ["other", "other", "main"]);
+        assert_eq!(lines[1]["repeat_count"], 1);
+    }
+
+    #[test]
+    fn writes_summaries_outside_of_any_span() {
+        let layer = layer().with_tail_sampling(TailSampling::new(0.0));
+        let captured = capture(layer, || {
+            {
+                let span = tracing::info_span!("request", request = 1);
+                let _span = span.enter();
+                for _ in 0..3 {
+                    tracing::error!("failed");
+                }
+            }
+            // The first span has closed, so this one may well get its id. Had the summary gone in
+            // this span's buffer, its error would have had the span written.
+            let span = tracing::info_span!("request", request = 2);
+            let _span = span.enter();
+            tracing::info!("other");
+        });
+        let lines = captured.json();
+
+        let messages: Vec<_> = lines.iter().map(|line| &line["message"]).collect();
+        assert_eq!(messages, ["failed", "failed"]);
+        assert_eq!(lines[1]["repeat_count"], 2);
+        assert_eq!(lines[1]["span"]["request"], 1);
+    }
+}
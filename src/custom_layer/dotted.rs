@@ -0,0 +1,206 @@
+//! Expand dotted field names like `http.method` and `http.status` into nested objects:
+//!
+//! ```json
+//! { "http": { "method": "GET", "status": 200 } }
+//! ```
+//!
+//! Expanded fields are merged into any object already recorded under the same prefix (e.g. a
+//! `valuable` value logged as `http`). If a key would have to be both a leaf and an object, the
+//! dotted field is left as it was and the conflict is reported.
+
+use serde_json::{Map, Value};
+
+use super::Fields;
+
+/// Expand the dotted names in `fields`, returning the names of the fields that conflicted.
+pub(crate) fn expand(fields: &mut Fields) -> Vec<String> {
+    if !fields.keys().any(|key| key.contains('.')) {
+        return Vec::new();
+    }
+
+    // Undotted fields go in first, so that dotted fields can be merged into them no matter what
+    // order they were recorded in.
+    let (dotted, mut expanded): (Fields, Fields) = std::mem::take(fields)
+        .into_iter()
+        .partition(|(key, _)| key.contains('.'));
+
+    let mut conflicts = Vec::new();
+    for (key, value) in dotted {
+        let mut path = key.split('.');
+        let first = path.next().unwrap_or_default();
+        let rest: Vec<&str> = path.collect();
+
+        let merged = match expanded.get_mut(first) {
+            Some(existing) => insert(existing, &rest, value),
+            None => {
+                expanded.insert(first.to_owned().into(), nest(&rest, value));
+                Ok(())
+            }
+        };
+        if let Err(value) = merged {
+            conflicts.push(key.to_string());
+            expanded.insert(key, value);
+        }
+    }
+
+    *fields = expanded;
+    conflicts
+}
+
+/// Insert a value at a path inside `target`, creating objects along the way. If that isn't
+/// possible without turning a leaf into an object (or vice versa), the value is handed back.
+fn insert(target: &mut Value, path: &[&str], value: Value) -> Result<(), Value> {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return merge(target, value),
+    };
+    let object = match target {
+        Value::Object(object) => object,
+        _ => return Err(value),
+    };
+    match object.get_mut(*first) {
+        Some(child) => insert(child, rest, value),
+        None => {
+            object.insert(first.to_string(), nest(rest, value));
+            Ok(())
+        }
+    }
+}
+
+/// Wrap a value in an object for each part of a path: `["a", "b"]` and `1` become `{"a":{"b":1}}`.
+fn nest(path: &[&str], value: Value) -> Value {
+    path.iter().rev().fold(value, |value, key| {
+        Value::Object(Map::from_iter([(key.to_string(), value)]))
+    })
+}
+
+/// Merge `value` into `target`. Objects are merged key by key, and leaves replace leaves, but a
+/// leaf and an object conflict.
+fn merge(target: &mut Value, value: Value) -> Result<(), Value> {
+    match (target, value) {
+        (Value::Object(target), Value::Object(value)) => {
+            // Check everything first, so that a conflict doesn't leave a half-merged object.
+            if !value
+                .iter()
+                .all(|(k, v)| target.get(k).is_none_or(|t| can_merge(t, v)))
+            {
+                return Err(Value::Object(value));
+            }
+            for (k, v) in value {
+                match target.get_mut(&k) {
+                    Some(t) => {
+                        let _ = merge(t, v);
+                    }
+                    None => {
+                        target.insert(k, v);
+                    }
+                }
+            }
+            Ok(())
+        }
+        (Value::Object(_), value) => Err(value),
+        (_, value @ Value::Object(_)) => Err(value),
+        (target, value) => {
+            *target = value;
+            Ok(())
+        }
+    }
+}
+
+fn can_merge(target: &Value, value: &Value) -> bool {
+    match (target, value) {
+        (Value::Object(target), Value::Object(value)) => value
+            .iter()
+            .all(|(k, v)| target.get(k).is_none_or(|t| can_merge(t, v))),
+        (Value::Object(_), _) | (_, Value::Object(_)) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::expand;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::{CustomJsonLayer, Fields};
+
+    /// Expand the fields of a JSON object, returning them as an object again and the conflicts.
+    fn expanded(fields: Value) -> (Value, Vec<String>) {
+        let Value::Object(object) = fields else {
+            panic!("not an object: {}", fields);
+        };
+        let mut fields: Fields = object.into_iter().map(|(k, v)| (k.into(), v)).collect();
+        let conflicts = expand(&mut fields);
+        let fields = fields.into_iter().map(|(k, v)| (k.into_owned(), v));
+        (Value::Object(fields.collect()), conflicts)
+    }
+
+    #[test]
+    fn nests_dotted_fields() {
+        let (fields, conflicts) =
+            expanded(json!({ "http.method": "GET", "user": 1, "http.request.id": 7 }));
+        assert_eq!(
+            fields,
+            json!({ "user": 1, "http": { "method": "GET", "request": { "id": 7 } } })
+        );
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn merges_into_objects_recorded_under_the_same_prefix() {
+        // Whichever order they were recorded in.
+        for fields in [
+            json!({ "http.method": "GET", "http": { "version": "1.1", "headers": { "a": 1 } } }),
+            json!({ "http": { "version": "1.1", "headers": { "a": 1 } }, "http.method": "GET" }),
+        ] {
+            let (fields, conflicts) = expanded(fields);
+            assert_eq!(
+                fields,
+                json!({ "http": { "version": "1.1", "headers": { "a": 1 }, "method": "GET" } })
+            );
+            assert!(conflicts.is_empty());
+        }
+
+        let (fields, _) =
+            expanded(json!({ "http": { "headers": { "a": 1 } }, "http.headers": { "b": 2 } }));
+        assert_eq!(fields, json!({ "http": { "headers": { "a": 1, "b": 2 } } }));
+    }
+
+    #[test]
+    fn leaves_conflicting_fields_alone() {
+        for fields in [json!({ "a": 1, "a.b": 2 }), json!({ "a.b": 2, "a": 1 })] {
+            let (fields, conflicts) = expanded(fields);
+            assert_eq!(fields, json!({ "a": 1, "a.b": 2 }));
+            assert_eq!(conflicts, ["a.b"]);
+        }
+
+        let (fields, conflicts) = expanded(json!({ "a.b": 1, "a.b.c": 2 }));
+        assert_eq!(fields, json!({ "a": { "b": 1 }, "a.b.c": 2 }));
+        assert_eq!(conflicts, ["a.b.c"]);
+
+        // An object that can't be merged in whole isn't merged in at all.
+        let (fields, conflicts) =
+            expanded(json!({ "a": { "b": { "c": 1 } }, "a.b": { "d": 2, "c": { "e": 3 } } }));
+        assert_eq!(
+            fields,
+            json!({ "a": { "b": { "c": 1 } }, "a.b": { "d": 2, "c": { "e": 3 } } })
+        );
+        assert_eq!(conflicts, ["a.b"]);
+    }
+
+    #[test]
+    fn writes_where_fields_conflicted() {
+        let layer = CustomJsonLayer::default().with_dotted_field_expansion(true);
+        let captured = capture(layer, || {
+            let _span = tracing::info_span!("request", http = 1, http.method = "GET").entered();
+            tracing::info!(a = 1, a.b = 2, c.d = 3, "hi");
+        });
+        let line = &captured.json()[0];
+        assert_eq!(line["fields"], json!({ "a": 1, "a.b": 2, "c": { "d": 3 } }));
+        assert_eq!(
+            line["field_conflicts"],
+            json!(["fields.a.b", "spans[0].http.method"])
+        );
+    }
+}
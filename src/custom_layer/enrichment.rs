@@ -0,0 +1,198 @@
+//! Extra top-level keys (like `service`, `version`, or `host`) written on every line.
+//!
+//! Static values are known up front, so they're serialized once, the same way as the callsite
+//! fragments. Dynamic values come from providers that are called for every event.
+
+use serde::ser::SerializeMap;
+use serde_json::value::{to_raw_value, RawValue};
+use valuable::Valuable;
+
+use super::keys::KeyTransform;
+
+/// A function called for every event that provides the value of an enrichment key, or `None` to
+/// leave the key out.
+type Provider = Box<dyn Fn() -> Option<serde_json::Value> + Send + Sync>;
+
+enum EnrichedValue {
+    Static(Box<RawValue>),
+    Dynamic(Provider),
+}
+
+/// All of the enrichment keys, in the order they were configured.
+#[derive(Default)]
+pub(crate) struct Enrichment(Vec<(String, EnrichedValue)>);
+
+impl Enrichment {
+    pub fn push_static(&mut self, key: String, value: serde_json::Value) {
+        if let Ok(value) = to_raw_value(&value) {
+            self.0.push((key, EnrichedValue::Static(value)));
+        }
+    }
+
+    /// Read an environment variable once, skipping the key if it isn't set.
+    pub fn push_env(&mut self, key: String, var: &str) {
+        if let Ok(value) = std::env::var(var) {
+            self.push_static(key, serde_json::Value::String(value));
+        }
+    }
+
+    /// Read a file once (like a Kubernetes downward-API file), skipping the key if it can't be
+    /// read.
+    pub fn push_file(&mut self, key: String, path: &std::path::Path) {
+        if let Ok(value) = std::fs::read_to_string(path) {
+            self.push_static(key, serde_json::Value::String(value.trim().to_owned()));
+        }
+    }
+
+    pub fn push_dynamic<F>(&mut self, key: String, provider: F)
+    where
+        F: Fn() -> Option<serde_json::Value> + Send + Sync + 'static,
+    {
+        self.0
+            .push((key, EnrichedValue::Dynamic(Box::new(provider))));
+    }
+
+    pub fn push_dynamic_valuable<F, V>(&mut self, key: String, provider: F)
+    where
+        F: Fn() -> Option<V> + Send + Sync + 'static,
+        V: Valuable,
+    {
+        self.push_dynamic(key, move || {
+            let value = provider()?;
+            serde_json::to_value(valuable_serde::Serializable::new(&value)).ok()
+        });
+    }
+
+    pub fn serialize<M: SerializeMap>(
+        &self,
+        map: &mut M,
+        keys: &KeyTransform,
+    ) -> Result<(), M::Error> {
+        for (key, value) in &self.0 {
+            let key = keys.top_level(key);
+            match value {
+                EnrichedValue::Static(value) => map.serialize_entry(&key, &**value)?,
+                EnrichedValue::Dynamic(provider) => {
+                    if let Some(value) = provider() {
+                        map.serialize_entry(&key, &value)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::{CustomJsonLayer, KeyCase, KeyDepth, RESERVED_KEYS};
+
+    #[test]
+    fn writes_enrichment_keys_once() {
+        let layer = CustomJsonLayer::default().with_static_field("service", "api");
+        let captured = capture(layer, || tracing::info!("hello"));
+        let lines = captured.lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].matches("\"service\":").count(), 1);
+        assert_eq!(lines[0].matches("\"level\":").count(), 1);
+        assert_eq!(captured.json()[0]["service"], "api");
+    }
+
+    #[test]
+    fn reads_file_fields_once_and_trimmed() {
+        let path = std::env::temp_dir().join(format!("enrichment-{}", std::process::id()));
+        std::fs::write(&path, "pod-7\n").unwrap();
+        let layer = CustomJsonLayer::default()
+            .with_file_field("pod", &path)
+            .with_file_field("node", path.with_extension("missing"));
+        std::fs::remove_file(&path).unwrap();
+
+        let captured = capture(layer, || tracing::info!("hello"));
+        let line = &captured.json()[0];
+        assert_eq!(line["pod"], "pod-7");
+        assert_eq!(line.get("node"), None);
+    }
+
+    #[test]
+    fn calls_dynamic_field_providers_for_every_event() {
+        let calls = AtomicU64::new(0);
+        let layer = CustomJsonLayer::default()
+            .with_dynamic_field("call", move || {
+                Some(calls.fetch_add(1, Ordering::Relaxed).into())
+            })
+            .with_dynamic_field("nothing", || None);
+        let captured = capture(layer, || {
+            for _ in 0..3 {
+                tracing::info!("hello");
+            }
+        });
+        let lines = captured.json();
+        let calls: Vec<_> = lines.iter().map(|line| line["call"].clone()).collect();
+        assert_eq!(calls, [0, 1, 2]);
+        assert!(lines.iter().all(|line| line.get("nothing").is_none()));
+    }
+
+    #[test]
+    #[should_panic(expected = "`level` is one of the top-level keys")]
+    fn rejects_a_static_field_named_like_a_built_in_key() {
+        let _ = CustomJsonLayer::default().with_static_field("level", "x");
+    }
+
+    #[test]
+    #[should_panic(expected = "`fields` is one of the top-level keys")]
+    fn rejects_a_dynamic_field_named_like_a_built_in_key() {
+        let _ = CustomJsonLayer::default().with_dynamic_valuable_field("fields", || Some(1));
+    }
+
+    #[test]
+    fn rejects_every_key_the_layer_writes() {
+        for key in RESERVED_KEYS {
+            let result = std::panic::catch_unwind(|| {
+                CustomJsonLayer::default().with_static_field(*key, 1);
+            });
+            assert!(result.is_err(), "`{}` was accepted", key);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "`duration_ms` is one of the top-level keys")]
+    fn rejects_a_field_named_like_a_canonical_line_key() {
+        let _ = CustomJsonLayer::default().with_static_field("duration_ms", 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "`service` is already written as a top-level key")]
+    fn rejects_two_fields_with_the_same_key() {
+        let _ = CustomJsonLayer::default()
+            .with_static_field("service", "a")
+            .with_dynamic_field("service", || Some("b".into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "`level` is one of the top-level keys")]
+    fn rejects_a_rename_onto_a_built_in_key() {
+        let _ = CustomJsonLayer::default()
+            .with_static_field("severity", "high")
+            .with_key_rename("severity", "level");
+    }
+
+    #[test]
+    #[should_panic(expected = "`repeatCount` is one of the top-level keys")]
+    fn rejects_a_field_that_clashes_once_its_case_is_converted() {
+        let _ = CustomJsonLayer::default()
+            .with_key_case(KeyCase::Camel, KeyDepth::TopLevel)
+            .with_static_field("repeatCount", 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "`hostName` is already written as a top-level key")]
+    fn rejects_fields_that_clash_with_each_other_once_their_case_is_converted() {
+        let _ = CustomJsonLayer::default()
+            .with_static_field("host_name", "a")
+            .with_static_field("hostName", "b")
+            .with_key_case(KeyCase::Camel, KeyDepth::TopLevel);
+    }
+}
@@ -0,0 +1,123 @@
+//! Lets `filter_expr` filters look things up in an `EventData` without serializing it first.
+//!
+//! Paths follow the shape of the JSON the layer writes: `level`, `target`, `message`,
+//! `fields.user_id`, `context.request_id`, `spans[0].name`, and so on. `span` paths are looked up
+//! the way `filter_expr` describes: `span.name` and `span.target` are the closest span's, but
+//! `span.request_path` (or `span.fields.request_path`) finds the field in whichever span in scope
+//! is closest to the event, no matter which span output mode is used.
+
+use serde_json::{json, Value};
+use std::borrow::Cow;
+
+use super::{format_level, EventData, Fields, SpanData};
+use crate::filter_expr::{resolve_in, PathSegment, Resolve};
+
+impl Resolve for EventData {
+    fn resolve(&self, path: &[PathSegment]) -> Option<Cow<'_, Value>> {
+        let (root, rest) = split_key(path)?;
+        match root {
+            "timestamp" if rest.is_empty() => Some(Cow::Owned(json!(self.timestamp))),
+            "level" if rest.is_empty() => {
+                Some(Cow::Owned(json!(format_level(self.metadata().level()))))
+            }
+            "target" if rest.is_empty() => Some(Cow::Owned(json!(self.metadata().target()))),
+            "message" => resolve_value(self.message.as_ref()?, rest),
+            "message_template" => resolve_value(self.message_template.as_ref()?, rest),
+            "fields" => resolve_fields(&self.fields, rest),
+            "context" => resolve_fields(self.context.as_ref()?, rest),
+            "span" => {
+                let (key, rest) = match split_key(rest)? {
+                    ("name" | "target", []) => return resolve_span(self.spans.last()?, rest),
+                    ("fields", rest) => split_key(rest)?,
+                    (key, rest) => (key, rest),
+                };
+                let value = self
+                    .spans
+                    .iter()
+                    .rev()
+                    .find_map(|span| span.fields.get(key))?;
+                resolve_value(value, rest)
+            }
+            "spans" => match rest.split_first()? {
+                (PathSegment::Index(index), rest) => resolve_span(self.spans.get(*index)?, rest),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn resolve_span<'a>(span: &'a SpanData, path: &[PathSegment]) -> Option<Cow<'a, Value>> {
+    match split_key(path)? {
+        ("name", []) => Some(Cow::Owned(json!(span.metadata().name()))),
+        ("target", []) => Some(Cow::Owned(json!(span.metadata().target()))),
+        ("fields", rest) => resolve_fields(&span.fields, rest),
+        _ => resolve_fields(&span.fields, path),
+    }
+}
+
+fn resolve_fields<'a>(fields: &'a Fields, path: &[PathSegment]) -> Option<Cow<'a, Value>> {
+    match split_key(path) {
+        Some((key, rest)) => resolve_value(fields.get(key)?, rest),
+        None if path.is_empty() => Some(Cow::Owned(json!(fields))),
+        None => None,
+    }
+}
+
+fn resolve_value<'a>(value: &'a Value, path: &[PathSegment]) -> Option<Cow<'a, Value>> {
+    resolve_in(value, path).map(Cow::Borrowed)
+}
+
+fn split_key(path: &[PathSegment]) -> Option<(&str, &[PathSegment])> {
+    match path.split_first()? {
+        (PathSegment::Key(key), rest) => Some((key.as_str(), rest)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::{CustomJsonLayer, SpanLayout, SpanOutput};
+    use crate::filter_expr::Filter;
+
+    fn log_in_spans() {
+        let request = tracing::info_span!("request", path = "/admin");
+        let _request = request.enter();
+        tracing::info_span!("load", id = 7).in_scope(|| tracing::info!("loaded"));
+    }
+
+    /// A filter should find the same things in an event as in the line written for it, whichever
+    /// way the line lays its spans out.
+    #[test]
+    fn matches_events_and_their_lines_the_same_way() {
+        let filters = [
+            (r#"span.path == "/admin""#.to_owned(), true),
+            (r#"span.fields.path == "/admin""#.to_owned(), true),
+            ("span.id == 7".to_owned(), true),
+            (r#"span.name == "load""#.to_owned(), true),
+            (format!(r#"span.target == "{}""#, module_path!()), true),
+            (r#"span.name == "request""#.to_owned(), false),
+            ("span.missing".to_owned(), false),
+        ];
+        for layout in [SpanLayout::Flat, SpanLayout::Nested] {
+            for output in [SpanOutput::LeafAndAll, SpanOutput::All, SpanOutput::Merged] {
+                let layer = || {
+                    CustomJsonLayer::default()
+                        .with_span_layout(layout)
+                        .with_span_output(output)
+                };
+                let line = capture(layer(), log_in_spans).json().remove(0);
+                for (source, expected) in &filters {
+                    let filter = Filter::parse(source).unwrap();
+                    let context = format!("`{}` with {:?} and {:?}", source, layout, output);
+                    assert_eq!(filter.matches(&line), *expected, "line: {}", context);
+
+                    let layer = layer().with_filter(filter);
+                    let written = capture(layer, log_in_spans).lines().len();
+                    assert_eq!(written == 1, *expected, "event: {}", context);
+                }
+            }
+        }
+    }
+}
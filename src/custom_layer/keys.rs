@@ -0,0 +1,354 @@
+//! Renaming keys for consumers that want a different naming convention than Rust's snake_case, or
+//! that still expect some legacy key names.
+//!
+//! This is applied after the pipeline, right before an event is written, so processors and filters
+//! still see the field names as they were logged.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+use super::Fields;
+
+/// A naming convention for keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyCase {
+    /// `user_id`
+    Snake,
+    /// `userId`
+    Camel,
+    /// `user-id`
+    Kebab,
+}
+
+/// Which keys get their case converted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyDepth {
+    /// Only the top-level keys of each line, like `message_template` and `span_ids`.
+    TopLevel,
+    /// The top-level keys, and the names of event and span fields.
+    Fields,
+    /// Every key, including the ones nested inside field values.
+    Recursive,
+}
+
+#[derive(Default)]
+pub(crate) struct KeyTransform {
+    case: Option<(KeyCase, KeyDepth)>,
+    /// Explicit renames, which apply to top-level keys and field names and win over `case`.
+    renames: HashMap<String, String>,
+}
+
+impl KeyTransform {
+    pub fn set_case(&mut self, case: KeyCase, depth: KeyDepth) {
+        self.case = Some((case, depth));
+    }
+
+    pub fn rename(&mut self, from: String, to: String) {
+        self.renames.insert(from, to);
+    }
+
+    /// Transform a top-level key.
+    pub fn top_level<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        if let Some(renamed) = self.renames.get(key) {
+            return Cow::Owned(renamed.clone());
+        }
+        match self.case {
+            Some((case, _)) => Cow::Owned(convert(key, case)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Whether `fields` changes anything.
+    pub fn changes_fields(&self) -> bool {
+        self.field_case().is_some() || !self.renames.is_empty()
+    }
+
+    /// The case to convert field names to, and whether to convert the keys inside their values.
+    fn field_case(&self) -> Option<(KeyCase, bool)> {
+        self.case.and_then(|(case, depth)| match depth {
+            KeyDepth::TopLevel => None,
+            KeyDepth::Fields => Some((case, false)),
+            KeyDepth::Recursive => Some((case, true)),
+        })
+    }
+
+    /// Transform a field name.
+    pub fn field_name<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match (self.renames.get(key), self.field_case()) {
+            (Some(renamed), _) => Cow::Owned(renamed.clone()),
+            (None, Some((case, _))) => Cow::Owned(convert(key, case)),
+            (None, None) => Cow::Borrowed(key),
+        }
+    }
+
+    /// Transform one of the keys the layer writes inside a span object, like `name` or
+    /// `unset_fields`. These are converted like field names, but not renamed.
+    pub fn span_key<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self.field_case() {
+            Some((case, _)) => Cow::Owned(convert(key, case)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// Transform the names of fields and, if it's turned on, the keys inside their values.
+    ///
+    /// A name that would end up the same as another field's (like `user_id` next to `userId`)
+    /// keeps its original name instead, so neither value is lost. Those names are returned.
+    pub fn fields(&self, fields: &mut Fields) -> Vec<Cow<'static, str>> {
+        if !self.changes_fields() {
+            return Vec::new();
+        }
+        let case = self.field_case();
+
+        let entries: Vec<_> = std::mem::take(fields)
+            .into_iter()
+            .map(|(key, mut value)| {
+                if let Some((case, true)) = case {
+                    convert_nested(&mut value, case);
+                }
+                let converted = self.field_name(&key).into_owned();
+                (key, converted, value)
+            })
+            .collect();
+        let unchanged: HashSet<Cow<'static, str>> = entries
+            .iter()
+            .filter(|(key, converted, _)| key == converted)
+            .map(|(key, _, _)| key.clone())
+            .collect();
+
+        let mut kept = Vec::new();
+        for (key, converted, value) in entries {
+            if key == converted {
+                fields.insert(key, value);
+            } else if unchanged.contains(converted.as_str())
+                || fields.contains_key(converted.as_str())
+            {
+                kept.push(key.clone());
+                fields.insert(key, value);
+            } else {
+                fields.insert(Cow::Owned(converted), value);
+            }
+        }
+        kept
+    }
+}
+
+fn convert_nested(value: &mut serde_json::Value, case: KeyCase) {
+    match value {
+        serde_json::Value::Object(object) => {
+            let entries: Vec<_> = std::mem::take(object)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    convert_nested(&mut value, case);
+                    let converted = convert(&key, case);
+                    (key, converted, value)
+                })
+                .collect();
+            let unchanged: HashSet<String> = entries
+                .iter()
+                .filter(|(key, converted, _)| key == converted)
+                .map(|(key, _, _)| key.clone())
+                .collect();
+            // As with fields, a key that would clash with another keeps its original name.
+            for (key, converted, value) in entries {
+                if key != converted
+                    && !unchanged.contains(&converted)
+                    && !object.contains_key(&converted)
+                {
+                    object.insert(converted, value);
+                } else {
+                    object.insert(key, value);
+                }
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for value in array {
+                convert_nested(value, case);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Convert a key to a naming convention. Dotted keys like `http.status_code` are converted one
+/// segment at a time, and leading underscores are kept.
+fn convert(key: &str, case: KeyCase) -> String {
+    let mut converted = String::with_capacity(key.len());
+    for (i, segment) in key.split('.').enumerate() {
+        if i > 0 {
+            converted.push('.');
+        }
+        let trimmed = segment.trim_start_matches('_');
+        converted.push_str(&segment[..segment.len() - trimmed.len()]);
+
+        for (j, word) in words(trimmed).iter().enumerate() {
+            match case {
+                KeyCase::Snake | KeyCase::Kebab if j > 0 => {
+                    converted.push(if case == KeyCase::Snake { '_' } else { '-' });
+                    converted.push_str(word);
+                }
+                KeyCase::Camel if j > 0 => {
+                    let mut chars = word.chars();
+                    if let Some(first) = chars.next() {
+                        converted.extend(first.to_uppercase());
+                        converted.push_str(chars.as_str());
+                    }
+                }
+                _ => converted.push_str(word),
+            }
+        }
+    }
+    converted
+}
+
+/// Split a key into lowercase words, on `_`, `-`, and changes in case (keeping acronyms together,
+/// so `HTTPServer` is `http` and `server`).
+fn words(key: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let chars: Vec<char> = key.chars().collect();
+    for (i, &c) in chars.iter().enumerate() {
+        if c == '_' || c == '-' {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+        if c.is_uppercase() && !word.is_empty() {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            if !prev.is_uppercase() || next_is_lower {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+        word.extend(c.to_lowercase());
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{convert, KeyCase, KeyDepth};
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::{CustomJsonLayer, SpanLayout, UnsetFields};
+    use crate::macros::tracing_json_old_helper;
+
+    #[test]
+    fn converts_keys_to_each_case() {
+        for (key, snake, camel, kebab) in [
+            ("user_id", "user_id", "userId", "user-id"),
+            ("userId", "user_id", "userId", "user-id"),
+            ("HTTPServer", "http_server", "httpServer", "http-server"),
+            (
+                "_private_key",
+                "_private_key",
+                "_privateKey",
+                "_private-key",
+            ),
+            (
+                "http.status_code",
+                "http.status_code",
+                "http.statusCode",
+                "http.status-code",
+            ),
+        ] {
+            assert_eq!(convert(key, KeyCase::Snake), snake);
+            assert_eq!(convert(key, KeyCase::Camel), camel);
+            assert_eq!(convert(key, KeyCase::Kebab), kebab);
+        }
+    }
+
+    #[test]
+    fn converts_keys_to_each_depth() {
+        let user = tracing_json_old_helper(&json!({ "first_name": "a" }));
+        // The top-level keys are converted at every depth.
+        for (depth, fields) in [
+            (
+                KeyDepth::TopLevel,
+                json!({ "user_id": 1, "user": { "first_name": "a" } }),
+            ),
+            (
+                KeyDepth::Fields,
+                json!({ "user-id": 1, "user": { "first_name": "a" } }),
+            ),
+            (
+                KeyDepth::Recursive,
+                json!({ "user-id": 1, "user": { "first-name": "a" } }),
+            ),
+        ] {
+            let layer = CustomJsonLayer::default()
+                .with_message_templates(true)
+                .with_key_case(KeyCase::Kebab, depth);
+            let captured = capture(layer, || {
+                tracing::info!(user_id = 1, user = user.as_str(), message = "{user_id}");
+            });
+            let line = &captured.json()[0];
+            assert_eq!(line["message-template"], "{user_id}", "{:?}", depth);
+            assert_eq!(line["fields"], fields, "{:?}", depth);
+        }
+    }
+
+    #[test]
+    fn keeps_both_fields_when_names_collide() {
+        let layer = CustomJsonLayer::default().with_key_case(KeyCase::Camel, KeyDepth::Recursive);
+        let captured = capture(layer, || {
+            tracing::info!(user_id = 1, userId = 2, "hi");
+            tracing::info!(request_id = 3, requestId = 4, "hi");
+        });
+        let lines = captured.json();
+
+        assert_eq!(lines[0]["fields"]["userId"], 2);
+        assert_eq!(lines[0]["fields"]["user_id"], 1);
+        assert_eq!(
+            lines[0]["warnings"],
+            json!([
+                "fields.user_id kept its name, because renaming it would have clashed with another field"
+            ])
+        );
+        // The key that's already in the right case wins, whichever comes first.
+        assert_eq!(lines[1]["fields"]["requestId"], 4);
+        assert_eq!(lines[1]["fields"]["request_id"], 3);
+    }
+
+    #[test]
+    fn keeps_both_nested_keys_when_names_collide() {
+        let layer = CustomJsonLayer::default().with_key_case(KeyCase::Camel, KeyDepth::Recursive);
+        let user = tracing_json_old_helper(&json!({ "first_name": "a", "firstName": "b" }));
+        let captured = capture(layer, || tracing::info!(user = user.as_str(), "hi"));
+        assert_eq!(
+            captured.json()[0]["fields"]["user"],
+            json!({ "first_name": "a", "firstName": "b" })
+        );
+    }
+
+    #[test]
+    fn converts_span_objects() {
+        let layer = CustomJsonLayer::default()
+            .with_key_case(KeyCase::Camel, KeyDepth::Fields)
+            .with_unset_fields(UnsetFields::List)
+            .with_span_layout(SpanLayout::Nested);
+        let captured = capture(layer, || {
+            let span = tracing::info_span!("load", model_id = 7, loaded_at = tracing::field::Empty);
+            let _span = span.enter();
+            tracing::info!("hi");
+        });
+        let line = &captured.json()[0];
+        assert_eq!(
+            line["span"],
+            json!({
+                "name": "load",
+                "target": module_path!(),
+                "fields": { "modelId": 7 },
+                "unsetFields": ["loadedAt"],
+            })
+        );
+        assert_eq!(line["spans"][0], line["span"]);
+    }
+}
@@ -0,0 +1,107 @@
+//! Message templates: `message = "user {user_id} logged in"` is rendered using the event's own
+//! fields, so the message reads nicely while the raw template can still be used for grouping.
+
+use super::Fields;
+
+/// Render a message template by replacing every `{field}` with the value of that field.
+///
+/// Placeholders for fields the event doesn't have are left alone, and `{{` and `}}` can be used
+/// for literal braces. Returns `None` if the message isn't a template at all.
+pub(crate) fn render(template: &str, fields: &Fields) -> Option<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut is_template = false;
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            rendered.push_str(&rest[..1]);
+            rest = &rest[2..];
+            is_template = true;
+            continue;
+        }
+
+        let placeholder = rest.find('}').filter(|_| rest.starts_with('{'));
+        match placeholder.map(|end| (&rest[1..end], end)) {
+            Some((name, end)) if fields.contains_key(name) => {
+                match &fields[name] {
+                    serde_json::Value::String(s) => rendered.push_str(s),
+                    value => rendered.push_str(&value.to_string()),
+                }
+                rest = &rest[end + 1..];
+                is_template = true;
+            }
+            _ => {
+                rendered.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+
+    if is_template {
+        Some(rendered)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::render;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::{CustomJsonLayer, Fields};
+
+    fn fields() -> Fields {
+        Fields::from([("user".into(), json!("ana")), ("count".into(), json!(3))])
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        let rendered = render("{user} has {count} items", &fields());
+        assert_eq!(rendered.as_deref(), Some("ana has 3 items"));
+        assert_eq!(render("no placeholders", &fields()), None);
+    }
+
+    #[test]
+    fn writes_escaped_braces_literally() {
+        let rendered = render("{{user}} is {user}, }} and {{", &fields());
+        assert_eq!(rendered.as_deref(), Some("{user} is ana, } and {"));
+        assert_eq!(render("{{}}", &fields()).as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn leaves_unclosed_and_unknown_placeholders_alone() {
+        assert_eq!(render("{user", &fields()), None);
+        assert_eq!(render("} {nobody}", &fields()), None);
+        let rendered = render("{count} {nobody} {user", &fields());
+        assert_eq!(rendered.as_deref(), Some("3 {nobody} {user"));
+    }
+
+    #[test]
+    fn keeps_the_template_next_to_the_rendered_message() {
+        for top_level_message in [true, false] {
+            let layer = CustomJsonLayer::default()
+                .with_message_templates(true)
+                .with_top_level_message(top_level_message);
+            let captured = capture(layer, || {
+                tracing::info!(user = "ana", message = "{user} logged in");
+            });
+            let line = &captured.json()[0];
+
+            let (message, in_fields) = if top_level_message {
+                (&line["message"], line["fields"].get("message"))
+            } else {
+                (&line["fields"]["message"], line.get("message"))
+            };
+            assert_eq!(message, "ana logged in");
+            assert_eq!(in_fields, None);
+            assert_eq!(line["message_template"], "{user} logged in");
+            assert_eq!(line["fields"]["user"], "ana");
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Keep numbers safe to read for consumers that parse JSON numbers as doubles (like JavaScript),
+//! and keep non-finite floats from silently turning into `null`.
+
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// The biggest integer a double can hold exactly, `2^53 - 1`. A good choice for
+/// `CustomJsonLayer::with_large_integer_limit`.
+pub const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// How to write floats that JSON can't represent: `NaN`, `Infinity`, and `-Infinity`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NonFiniteFloats {
+    /// As `null`.
+    #[default]
+    Null,
+    /// As `null`, with a warning on the event saying which field it was in.
+    NullWithWarning,
+    /// As the strings `"NaN"`, `"Infinity"`, and `"-Infinity"`.
+    String,
+}
+
+/// The integers a `NumericPolicy` wrote as strings, noted as they're written so that type stability
+/// can still count them as numbers without guessing from what a string looks like.
+pub(crate) type Stringified = HashSet<String>;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct NumericPolicy {
+    /// Integers bigger than this, in either direction, are written as strings.
+    pub large_integer_limit: Option<u64>,
+    pub non_finite_floats: NonFiniteFloats,
+}
+
+/// Write an integer as a string, noting that it was one.
+fn stringify(value: impl ToString, stringified: &mut Stringified) -> Value {
+    let value = value.to_string();
+    stringified.insert(value.clone());
+    Value::String(value)
+}
+
+impl NumericPolicy {
+    /// Convert an integer, adding it to `stringified` if it's written as a string. The same goes
+    /// for the other integer types.
+    pub fn u64(&self, value: u64, stringified: &mut Stringified) -> Value {
+        match self.large_integer_limit {
+            Some(limit) if value > limit => stringify(value, stringified),
+            _ => value.into(),
+        }
+    }
+
+    pub fn i64(&self, value: i64, stringified: &mut Stringified) -> Value {
+        match self.large_integer_limit {
+            Some(limit) if value.unsigned_abs() > limit => stringify(value, stringified),
+            _ => value.into(),
+        }
+    }
+
+    /// 128-bit integers follow the same rules, but anything that doesn't fit in 64 bits is always
+    /// written as a string, since it can't be a `serde_json::Value` number.
+    pub fn i128(&self, value: i128, stringified: &mut Stringified) -> Value {
+        match i64::try_from(value) {
+            Ok(value) => self.i64(value, stringified),
+            Err(_) => stringify(value, stringified),
+        }
+    }
+
+    pub fn u128(&self, value: u128, stringified: &mut Stringified) -> Value {
+        match u64::try_from(value) {
+            Ok(value) => self.u64(value, stringified),
+            Err(_) => stringify(value, stringified),
+        }
+    }
+
+    /// Convert a float, and whether it should be warned about.
+    pub fn f64(&self, value: f64) -> (Value, bool) {
+        if let Some(number) = serde_json::Number::from_f64(value) {
+            return (Value::Number(number), false);
+        }
+        match self.non_finite_floats {
+            NonFiniteFloats::Null => (Value::Null, false),
+            NonFiniteFloats::NullWithWarning => (Value::Null, true),
+            NonFiniteFloats::String => {
+                let value = if value.is_nan() {
+                    "NaN"
+                } else if value > 0.0 {
+                    "Infinity"
+                } else {
+                    "-Infinity"
+                };
+                (Value::String(value.to_owned()), false)
+            }
+        }
+    }
+
+    /// Apply the policy to every number in some JSON that has already been built. JSON numbers
+    /// are always finite, so this only affects large integers.
+    pub fn json(&self, value: &mut Value, stringified: &mut Stringified) {
+        if self.large_integer_limit.is_none() {
+            return;
+        }
+        match value {
+            Value::Number(number) => {
+                if let Some(n) = number.as_u64() {
+                    *value = self.u64(n, stringified);
+                } else if let Some(n) = number.as_i64() {
+                    *value = self.i64(n, stringified);
+                }
+            }
+            Value::Array(array) => array
+                .iter_mut()
+                .for_each(|value| self.json(value, stringified)),
+            Value::Object(object) => object
+                .values_mut()
+                .for_each(|value| self.json(value, stringified)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::NonFiniteFloats;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    fn log_non_finite_floats(non_finite_floats: NonFiniteFloats) -> serde_json::Value {
+        let layer = CustomJsonLayer::default().with_non_finite_floats(non_finite_floats);
+        let captured = capture(layer, || {
+            let _span = tracing::info_span!("load", ratio = f64::NAN).entered();
+            tracing::info!(
+                nan = f64::NAN,
+                up = f64::INFINITY,
+                down = f64::NEG_INFINITY,
+                fine = 0.5,
+                "measured"
+            );
+        });
+        captured.json().remove(0)
+    }
+
+    #[test]
+    fn writes_non_finite_floats_as_null() {
+        let line = log_non_finite_floats(NonFiniteFloats::Null);
+        assert_eq!(
+            line["fields"],
+            json!({ "nan": null, "up": null, "down": null, "fine": 0.5 })
+        );
+        assert_eq!(line["span"]["ratio"], json!(null));
+        assert_eq!(line.get("warnings"), None);
+    }
+
+    #[test]
+    fn warns_about_non_finite_floats_written_as_null() {
+        let line = log_non_finite_floats(NonFiniteFloats::NullWithWarning);
+        assert_eq!(
+            line["fields"],
+            json!({ "nan": null, "up": null, "down": null, "fine": 0.5 })
+        );
+        assert_eq!(
+            line["warnings"],
+            json!([
+                "fields.nan had a NaN or infinite number, which was written as null",
+                "fields.up had a NaN or infinite number, which was written as null",
+                "fields.down had a NaN or infinite number, which was written as null",
+                "spans.load.ratio had a NaN or infinite number, which was written as null",
+            ])
+        );
+    }
+
+    #[test]
+    fn writes_non_finite_floats_as_strings() {
+        let line = log_non_finite_floats(NonFiniteFloats::String);
+        assert_eq!(
+            line["fields"],
+            json!({ "nan": "NaN", "up": "Infinity", "down": "-Infinity", "fine": 0.5 })
+        );
+        assert_eq!(line["span"]["ratio"], "NaN");
+        assert_eq!(line.get("warnings"), None);
+    }
+}
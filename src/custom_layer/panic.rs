@@ -0,0 +1,122 @@
+//! A panic hook that logs panics as ERROR events, so they go through `CustomJsonLayer` with the
+//! span scope they happened in, instead of only going to stderr as plain text.
+//!
+//! ```no_compile
+//! PanicHook::new().with_flush(move || guard.flush()).install();
+//! ```
+
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::Cell;
+use std::panic::PanicHookInfo;
+
+thread_local! {
+    /// Set while the hook is logging, so that a panic while logging a panic doesn't recurse.
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Builds and installs the panic hook.
+#[derive(Default)]
+pub struct PanicHook {
+    flush: Option<Box<dyn Fn() + Send + Sync>>,
+    force_backtrace: bool,
+}
+
+impl PanicHook {
+    pub fn new() -> Self {
+        PanicHook::default()
+    }
+
+    /// Something to call after the panic is logged, to flush a writer that buffers lines (like a
+    /// non-blocking writer's guard), so the line isn't lost if the panic takes the process down.
+    pub fn with_flush(mut self, flush: impl Fn() + Send + Sync + 'static) -> Self {
+        self.flush = Some(Box::new(flush));
+        self
+    }
+
+    /// Whether to capture a backtrace even if `RUST_BACKTRACE` isn't set.
+    pub fn with_forced_backtrace(mut self, force_backtrace: bool) -> Self {
+        self.force_backtrace = force_backtrace;
+        self
+    }
+
+    /// Install the hook. The hook that was installed before (usually the default one, which prints
+    /// to stderr) still runs after the panic has been logged.
+    pub fn install(self) {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !IN_HOOK.with(|in_hook| in_hook.replace(true)) {
+                self.log(info);
+                if let Some(flush) = &self.flush {
+                    flush();
+                }
+                IN_HOOK.with(|in_hook| in_hook.set(false));
+            }
+            previous(info);
+        }));
+    }
+
+    fn log(&self, info: &PanicHookInfo<'_>) {
+        let payload = if let Some(payload) = info.payload().downcast_ref::<&str>() {
+            payload
+        } else if let Some(payload) = info.payload().downcast_ref::<String>() {
+            payload.as_str()
+        } else {
+            "Box<dyn Any>"
+        };
+        let location = info
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        let thread = std::thread::current();
+        let thread = thread.name().unwrap_or("<unnamed>");
+
+        let backtrace = if self.force_backtrace {
+            Backtrace::force_capture()
+        } else {
+            Backtrace::capture()
+        };
+        let backtrace = match backtrace.status() {
+            BacktraceStatus::Captured => Some(backtrace.to_string()),
+            _ => None,
+        };
+
+        // This is an ordinary event, so it's in whatever span the panic happened in.
+        tracing::error!(
+            message = "panicked",
+            payload,
+            location = location.as_str(),
+            thread,
+            backtrace = backtrace.as_deref(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PanicHook;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    #[test]
+    fn logs_panics_in_their_span() {
+        let captured = capture(CustomJsonLayer::default(), || {
+            PanicHook::new().with_forced_backtrace(true).install();
+            let panicked = std::panic::catch_unwind(|| {
+                let _span = tracing::info_span!("risky_spell").entered();
+                panic!("the spell backfired");
+            });
+            // Put the default hook back, for the rest of the tests.
+            let _ = std::panic::take_hook();
+            assert!(panicked.is_err());
+        });
+        let line = &captured.json()[0];
+
+        assert_eq!(line["level"], "ERROR");
+        assert_eq!(line["message"], "panicked");
+        assert_eq!(line["span"]["name"], "risky_spell");
+        let fields = &line["fields"];
+        assert_eq!(fields["payload"], "the spell backfired");
+        assert!(fields["location"].as_str().unwrap().contains("panic.rs"));
+        assert!(!fields["backtrace"].as_str().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+//! Ad-hoc transforms that run on every event before it's written: dropping a noisy field, renaming
+//! keys for a consumer, adding derived fields, and so on.
+//!
+//! Each `Processor` gets a mutable view of everything the layer collected for an event, and can
+//! change it, drop the event, or split it into several events. Processors run in the order they
+//! were added to the layer, and any events a processor splits off go through the rest of the
+//! pipeline too.
+//!
+//! ```no_compile
+//! let layer = CustomJsonLayer::default().with_processor(|event: &mut EventData| {
+//!     event.fields.shift_remove("password");
+//!     Processed::Keep
+//! });
+//! ```
+
+use chrono::{DateTime, Utc};
+use indexmap::IndexMap;
+use std::borrow::Cow;
+use tracing::{span, Metadata};
+
+use super::numeric::Stringified;
+use super::SpanMetadata;
+
+/// The fields of an event or span, in the order they were recorded.
+pub type Fields = IndexMap<Cow<'static, str>, serde_json::Value>;
+
+/// Everything the layer collected for an event, before it's written out.
+#[derive(Clone)]
+pub struct EventData {
+    pub timestamp: DateTime<Utc>,
+    pub(crate) metadata: SpanMetadata,
+    pub message: Option<serde_json::Value>,
+    pub message_template: Option<serde_json::Value>,
+    pub fields: Fields,
+    /// Span fields merged from the root span to the leaf, if `SpanContext::Object` is turned on.
+    pub context: Option<Fields>,
+    /// Every span the event is in, from the root to the leaf.
+    pub spans: Vec<SpanData>,
+    /// Extra top-level keys, written right before `fields`.
+    pub extra: Fields,
+    /// The integers the numeric policy wrote as strings, anywhere in the event or its spans.
+    pub(crate) stringified: Stringified,
+}
+
+impl EventData {
+    /// The event's level, target, and so on. These can't be changed by a processor.
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata.metadata
+    }
+}
+
+/// A copy of the data for one of the spans an event is in. Changing it only changes what's written
+/// for this event, not the span itself.
+#[derive(Clone)]
+pub struct SpanData {
+    pub id: span::Id,
+    pub(crate) metadata: SpanMetadata,
+    pub fields: Fields,
+    /// Fields declared on the span that haven't been recorded, unless `UnsetFields::Omit` is set.
+    pub unset: Vec<&'static str>,
+}
+
+impl SpanData {
+    /// The span's name, target, and so on.
+    pub fn metadata(&self) -> &'static Metadata<'static> {
+        self.metadata.metadata
+    }
+}
+
+/// What a processor decided to do with an event.
+pub enum Processed {
+    /// Keep the (possibly modified) event.
+    Keep,
+    /// Don't write the event at all.
+    Drop,
+    /// Replace the event with these events.
+    Split(Vec<EventData>),
+}
+
+/// Something that transforms events before they're written.
+///
+/// This is implemented for closures, so most processors can just be a `Fn(&mut EventData) ->
+/// Processed`.
+pub trait Processor: Send + Sync {
+    fn process(&self, event: &mut EventData) -> Processed;
+}
+
+impl<F> Processor for F
+where
+    F: Fn(&mut EventData) -> Processed + Send + Sync,
+{
+    fn process(&self, event: &mut EventData) -> Processed {
+        self(event)
+    }
+}
+
+/// All of the layer's processors, in order.
+#[derive(Default)]
+pub(crate) struct Pipeline(Vec<Box<dyn Processor>>);
+
+impl Pipeline {
+    pub fn push(&mut self, processor: Box<dyn Processor>) {
+        self.0.push(processor);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run an event through every processor, returning whatever events are left at the end.
+    pub fn run(&self, event: EventData) -> Vec<EventData> {
+        let mut events = vec![event];
+        for processor in &self.0 {
+            let mut processed = Vec::with_capacity(events.len());
+            for mut event in events {
+                match processor.process(&mut event) {
+                    Processed::Keep => processed.push(event),
+                    Processed::Drop => {}
+                    Processed::Split(split) => processed.extend(split),
+                }
+            }
+            events = processed;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{EventData, Processed};
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    #[test]
+    fn runs_split_events_through_the_rest_of_the_pipeline() {
+        let layer = CustomJsonLayer::default()
+            .with_processor(|event: &mut EventData| {
+                let Some(ids) = event.fields.shift_remove("ids") else {
+                    return Processed::Keep;
+                };
+                let split = ids
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|id| {
+                        let mut split = event.clone();
+                        split.fields.insert("id".into(), id.clone());
+                        split
+                    })
+                    .collect();
+                Processed::Split(split)
+            })
+            .with_processor(|event: &mut EventData| {
+                if event.fields.get("id") == Some(&json!(2)) {
+                    Processed::Drop
+                } else {
+                    Processed::Keep
+                }
+            });
+        let captured = capture(layer, || {
+            tracing::info!(ids = tracing::field::valuable(&vec![1, 2, 3]), "deleted");
+            tracing::info!("nothing to split");
+        });
+
+        let fields: Vec<_> = captured
+            .json()
+            .iter()
+            .map(|line| (line["message"].clone(), line["fields"].clone()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                (json!("deleted"), json!({ "id": 1 })),
+                (json!("deleted"), json!({ "id": 3 })),
+                (json!("nothing to split"), json!({})),
+            ]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Copy or move values nested inside fields up to top-level keys, for log indexers that only index
+//! top-level keys.
+//!
+//! A rule's source is a section (`fields` or `context`) followed by a JSON pointer into it,
+//! without the leading `/`. So `fields.model/id` is the `id` inside the `model` field, which is
+//! handy for values logged with `tracing_json_new!(model)`. As with any JSON pointer, `~1` and
+//! `~0` stand for `/` and `~` in keys.
+
+use super::{EventData, Fields, Processed};
+
+/// Whether a promoted value is also left where it was.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PromotionMode {
+    Copy,
+    Move,
+}
+
+/// A rule that promotes a nested value to a top-level key.
+#[derive(Clone, Debug)]
+pub struct Promotion {
+    section: Section,
+    field: String,
+    pointer: String,
+    key: String,
+    mode: PromotionMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Section {
+    Fields,
+    Context,
+}
+
+impl Promotion {
+    /// Copy the value at `source` (like `fields.model/id`) to the top-level `key`.
+    pub fn copy(source: &str, key: impl Into<String>) -> Self {
+        Promotion::new(source, key.into(), PromotionMode::Copy)
+    }
+
+    /// Move the value at `source` (like `fields.model/id`) to the top-level `key`.
+    pub fn moved(source: &str, key: impl Into<String>) -> Self {
+        Promotion::new(source, key.into(), PromotionMode::Move)
+    }
+
+    fn new(source: &str, key: String, mode: PromotionMode) -> Self {
+        // Sources without a section are in `fields`.
+        let (section, path) = match source.split_once('.') {
+            Some(("fields", path)) => (Section::Fields, path),
+            Some(("context", path)) => (Section::Context, path),
+            _ => (Section::Fields, source),
+        };
+        let (field, pointer) = match path.find('/') {
+            Some(slash) => (&path[..slash], &path[slash..]),
+            None => (path, ""),
+        };
+        Promotion {
+            section,
+            field: field.replace("~1", "/").replace("~0", "~"),
+            pointer: pointer.to_owned(),
+            key,
+            mode,
+        }
+    }
+
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub(crate) fn apply(&self, event: &mut EventData) -> Processed {
+        let fields = match self.section {
+            Section::Fields => &mut event.fields,
+            Section::Context => match &mut event.context {
+                Some(context) => context,
+                None => return Processed::Keep,
+            },
+        };
+        let value = match self.mode {
+            PromotionMode::Copy => self.find(fields).cloned(),
+            PromotionMode::Move => self.take(fields),
+        };
+        if let Some(value) = value {
+            event.extra.insert(self.key.clone().into(), value);
+        }
+        Processed::Keep
+    }
+
+    fn find<'a>(&self, fields: &'a Fields) -> Option<&'a serde_json::Value> {
+        fields.get(self.field.as_str())?.pointer(&self.pointer)
+    }
+
+    fn take(&self, fields: &mut Fields) -> Option<serde_json::Value> {
+        if self.pointer.is_empty() {
+            return fields.shift_remove(self.field.as_str());
+        }
+
+        // Find the parent of the value, so it can be removed from it rather than leaving a `null`
+        // behind.
+        let field = fields.get_mut(self.field.as_str())?;
+        let (parent, last) = self.pointer.rsplit_once('/')?;
+        let last = last.replace("~1", "/").replace("~0", "~");
+        match field.pointer_mut(parent)? {
+            serde_json::Value::Object(object) => object.remove(&last),
+            serde_json::Value::Array(array) => {
+                let index = last.parse().ok().filter(|index| *index < array.len())?;
+                Some(array.remove(index))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Promotion;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    #[test]
+    fn promotes_to_a_new_top_level_key() {
+        let layer = CustomJsonLayer::default().with_promotion(Promotion::moved("user", "user_id"));
+        let captured = capture(layer, || tracing::info!(user = "u_1", "hello"));
+        let line = &captured.json()[0];
+        assert_eq!(line["user_id"], "u_1");
+        assert!(line["fields"].get("user").is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "`message` is one of the top-level keys")]
+    fn rejects_a_promotion_to_a_built_in_key() {
+        let _ =
+            CustomJsonLayer::default().with_promotion(Promotion::copy("fields.text", "message"));
+    }
+
+    #[test]
+    #[should_panic(expected = "`service` is already written as a top-level key")]
+    fn rejects_a_promotion_to_an_enrichment_key() {
+        let _ = CustomJsonLayer::default()
+            .with_static_field("service", "a")
+            .with_promotion(Promotion::copy("fields.svc", "service"));
+    }
+
+    #[test]
+    #[should_panic(expected = "`model_id` is already written as a top-level key")]
+    fn rejects_two_promotions_to_the_same_key() {
+        let _ = CustomJsonLayer::default()
+            .with_promotion(Promotion::copy("fields.model/id", "model_id"))
+            .with_promotion(Promotion::moved("context.model_id", "model_id"));
+    }
+
+    #[test]
+    #[should_panic(expected = "`canonical` is one of the top-level keys")]
+    fn rejects_a_promotion_to_a_key_a_feature_writes() {
+        let _ =
+            CustomJsonLayer::default().with_promotion(Promotion::copy("fields.kind", "canonical"));
+    }
+}
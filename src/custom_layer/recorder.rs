@@ -0,0 +1,358 @@
+//! A flight recorder: keep the most recent events that are too verbose to write, and write them
+//! after all if an ERROR happens, so the error comes with the DEBUG context that led up to it.
+//!
+//! ```no_compile
+//! let layer = CustomJsonLayer::default().with_flight_recorder(
+//!     FlightRecorder::new(Level::INFO)
+//!         .with_scope(RecorderScope::RootSpan)
+//!         .with_capacity(200)
+//!         .with_window(Duration::from_secs(30)),
+//! );
+//! ```
+//!
+//! The recorder only sees events that reach the layer, so whatever filters the subscriber has
+//! need to let the verbose events through.
+//!
+//! A thread's buffer is thrown away when the thread exits, and a root span's when the span closes.
+//! At most `MAX_BUFFERS` buffers are kept at once: past that, the one that was recorded into
+//! longest ago is thrown away to make room.
+
+use std::cell::RefCell;
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+use tracing::{span, Level, Metadata};
+
+/// Which events share a buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecorderScope {
+    /// Each thread has its own buffer.
+    #[default]
+    Thread,
+    /// Each root span has its own buffer, which is thrown away when the span closes. Events
+    /// outside of any span use their thread's buffer.
+    RootSpan,
+}
+
+/// The most buffers a recorder keeps at once.
+const MAX_BUFFERS: usize = 4096;
+
+type Buffers = Mutex<HashMap<BufferKey, VecDeque<Recorded>>>;
+
+/// Buffers events less severe than a level, and writes them out before the next ERROR.
+pub struct FlightRecorder {
+    level: Level,
+    scope: RecorderScope,
+    capacity: usize,
+    window: Option<Duration>,
+    /// Shared with the `ThreadBuffers` of every thread that has a buffer in here.
+    buffers: Arc<Buffers>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) enum BufferKey {
+    Thread(ThreadId),
+    Root(span::Id),
+}
+
+/// An event that has been serialized but not written.
+pub(crate) struct Recorded {
+    pub metadata: &'static Metadata<'static>,
+    pub line: Vec<u8>,
+    at: Instant,
+}
+
+impl FlightRecorder {
+    /// Record events less severe than `level` (so `Level::INFO` records DEBUG and TRACE events)
+    /// instead of writing them. Each buffer keeps the last 100 events by default.
+    pub fn new(level: Level) -> Self {
+        FlightRecorder {
+            level,
+            scope: RecorderScope::default(),
+            capacity: 100,
+            window: None,
+            buffers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_scope(mut self, scope: RecorderScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// How many events each buffer keeps. Older events are dropped to make room.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Only write out events from this long before the error.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Whether an event at this level should be recorded rather than written.
+    pub(crate) fn records(&self, level: &Level) -> bool {
+        *level > self.level
+    }
+
+    /// Which buffer an event in this root span (if any) goes in, or is flushed from.
+    pub(crate) fn key(&self, root: Option<&span::Id>) -> BufferKey {
+        match (self.scope, root) {
+            (RecorderScope::RootSpan, Some(root)) => BufferKey::Root(root.clone()),
+            _ => BufferKey::Thread(std::thread::current().id()),
+        }
+    }
+
+    pub(crate) fn record(
+        &self,
+        key: BufferKey,
+        metadata: &'static Metadata<'static>,
+        line: Vec<u8>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut buffers = lock(&self.buffers);
+        if !buffers.contains_key(&key) && buffers.len() >= MAX_BUFFERS {
+            let oldest = buffers
+                .iter()
+                .min_by_key(|(_, buffer)| buffer.back().map(|recorded| recorded.at))
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                buffers.remove(&oldest);
+            }
+        }
+        let buffer = match buffers.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                if let BufferKey::Thread(_) = entry.key() {
+                    // So that the buffer can be thrown away when the thread exits.
+                    let _ = THREAD_BUFFERS.try_with(|thread| thread.add(&self.buffers));
+                }
+                entry.insert(VecDeque::new())
+            }
+        };
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(Recorded {
+            metadata,
+            line,
+            at: Instant::now(),
+        });
+    }
+
+    /// Take everything in a buffer that's inside the window, oldest first.
+    pub(crate) fn take(&self, key: &BufferKey) -> Vec<Recorded> {
+        let buffer = match lock(&self.buffers).remove(key) {
+            Some(buffer) => buffer,
+            None => return Vec::new(),
+        };
+        let now = Instant::now();
+        buffer
+            .into_iter()
+            .filter(|recorded| {
+                self.window
+                    .is_none_or(|window| now.duration_since(recorded.at) <= window)
+            })
+            .collect()
+    }
+
+    /// Throw away a root span's buffer once the span has closed.
+    pub(crate) fn close(&self, id: &span::Id) {
+        if self.scope == RecorderScope::RootSpan {
+            lock(&self.buffers).remove(&BufferKey::Root(id.clone()));
+        }
+    }
+}
+
+fn lock(buffers: &Buffers) -> MutexGuard<'_, HashMap<BufferKey, VecDeque<Recorded>>> {
+    match buffers.lock() {
+        Ok(buffers) => buffers,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+thread_local! {
+    static THREAD_BUFFERS: ThreadBuffers = ThreadBuffers {
+        thread: std::thread::current().id(),
+        recorders: RefCell::new(Vec::new()),
+    };
+}
+
+/// The recorders a thread has a buffer in, whose buffers are thrown away when the thread exits.
+struct ThreadBuffers {
+    thread: ThreadId,
+    recorders: RefCell<Vec<Weak<Buffers>>>,
+}
+
+impl ThreadBuffers {
+    fn add(&self, buffers: &Arc<Buffers>) {
+        let mut recorders = self.recorders.borrow_mut();
+        recorders.retain(|recorder| recorder.strong_count() > 0);
+        if !recorders
+            .iter()
+            .any(|recorder| std::ptr::eq(recorder.as_ptr(), Arc::as_ptr(buffers)))
+        {
+            recorders.push(Arc::downgrade(buffers));
+        }
+    }
+}
+
+impl Drop for ThreadBuffers {
+    fn drop(&mut self) {
+        let key = BufferKey::Thread(self.thread);
+        for recorder in self.recorders.get_mut().drain(..) {
+            if let Some(buffers) = recorder.upgrade() {
+                lock(&buffers).remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tracing::{span, Callsite, Level};
+
+    use super::{lock, BufferKey, FlightRecorder, RecorderScope, MAX_BUFFERS};
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    /// The messages written with a flight recorder, with `+` in front of backfilled ones.
+    fn written(recorder: FlightRecorder, f: impl FnOnce()) -> Vec<String> {
+        let layer = CustomJsonLayer::default().with_flight_recorder(recorder);
+        capture(layer, f)
+            .json()
+            .iter()
+            .map(|line| {
+                let backfilled = line.get("backfilled") == Some(&serde_json::Value::Bool(true));
+                let message = line["message"].as_str().unwrap();
+                format!("{}{}", if backfilled { "+" } else { "" }, message)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writes_recorded_events_before_an_error() {
+        let written = written(FlightRecorder::new(Level::INFO), || {
+            tracing::debug!("connecting");
+            tracing::info!("started");
+            tracing::trace!("sent");
+            tracing::error!("failed");
+            tracing::debug!("retrying");
+            tracing::error!("failed again");
+            tracing::debug!("gave up");
+        });
+        assert_eq!(
+            written,
+            [
+                "started",
+                "+connecting",
+                "+sent",
+                "failed",
+                "+retrying",
+                "failed again"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_last_events_up_to_the_capacity() {
+        let written = written(FlightRecorder::new(Level::INFO).with_capacity(2), || {
+            for message in ["one", "two", "three"] {
+                tracing::debug!(message);
+            }
+            tracing::error!("failed");
+        });
+        assert_eq!(written, ["+two", "+three", "failed"]);
+    }
+
+    #[test]
+    fn only_writes_events_inside_the_window() {
+        let recorder = FlightRecorder::new(Level::INFO).with_window(Duration::from_millis(50));
+        let written = written(recorder, || {
+            tracing::debug!("long ago");
+            std::thread::sleep(Duration::from_millis(100));
+            tracing::debug!("just now");
+            tracing::error!("failed");
+        });
+        assert_eq!(written, ["+just now", "failed"]);
+    }
+
+    #[test]
+    fn keeps_a_buffer_for_each_thread() {
+        let written = written(FlightRecorder::new(Level::INFO), || {
+            let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+            tracing::debug!("here");
+            std::thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || tracing::debug!("elsewhere"));
+            })
+            .join()
+            .unwrap();
+            // Spans don't matter with this scope.
+            let _span = tracing::info_span!("request").entered();
+            tracing::debug!("in a span");
+            tracing::error!("failed");
+        });
+        assert_eq!(written, ["+here", "+in a span", "failed"]);
+    }
+
+    #[test]
+    fn keeps_a_buffer_for_each_root_span() {
+        let recorder = FlightRecorder::new(Level::INFO).with_scope(RecorderScope::RootSpan);
+        let written = written(recorder, || {
+            tracing::debug!("outside");
+            let first = tracing::info_span!("request");
+            let second = tracing::info_span!("request");
+            first.in_scope(|| tracing::debug!("first"));
+            second.in_scope(|| {
+                tracing::debug!("second");
+                tracing::info_span!("query").in_scope(|| tracing::error!("failed"));
+            });
+            tracing::error!("failed outside");
+        });
+        assert_eq!(written, ["+second", "failed", "+outside", "failed outside"]);
+    }
+
+    fn debug_metadata() -> &'static tracing::Metadata<'static> {
+        tracing::callsite! {
+            name: "recorded",
+            kind: tracing::metadata::Kind::EVENT,
+            level: Level::DEBUG,
+            fields: []
+        }
+        .metadata()
+    }
+
+    #[test]
+    fn drops_thread_buffers_when_the_thread_exits() {
+        let recorder = Arc::new(FlightRecorder::new(Level::INFO));
+        let thread = std::thread::spawn({
+            let recorder = recorder.clone();
+            move || {
+                let key = BufferKey::Thread(std::thread::current().id());
+                recorder.record(key.clone(), debug_metadata(), b"a\n".to_vec());
+                assert!(lock(&recorder.buffers).contains_key(&key));
+            }
+        });
+        thread.join().unwrap();
+        assert!(lock(&recorder.buffers).is_empty());
+    }
+
+    #[test]
+    fn drops_the_oldest_buffer_when_full() {
+        let recorder = FlightRecorder::new(Level::INFO);
+        for id in 1..=MAX_BUFFERS as u64 + 1 {
+            let key = BufferKey::Root(span::Id::from_u64(id));
+            recorder.record(key, debug_metadata(), b"a\n".to_vec());
+        }
+        let buffers = lock(&recorder.buffers);
+        assert_eq!(buffers.len(), MAX_BUFFERS);
+        assert!(!buffers.contains_key(&BufferKey::Root(span::Id::from_u64(1))));
+    }
+}
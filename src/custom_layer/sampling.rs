@@ -0,0 +1,207 @@
+//! Head sampling of whole traces: whether a trace is written is decided once, when its root span
+//! is created, and every span and event inside it goes along with that decision. Sampling events
+//! one at a time would leave every trace with holes in it.
+//!
+//! The decision is a hash of the root span's trace id field, so every service that sees the same
+//! trace id (and uses the same rate) makes the same decision, and a trace is either kept
+//! everywhere or nowhere. Root spans without a trace id are sampled at random.
+//!
+//! ```no_compile
+//! let layer = CustomJsonLayer::default().with_head_sampling(
+//!     HeadSampling::new(0.1)
+//!         .with_rate("health_check", 0.0)
+//!         .with_rate("checkout", 1.0),
+//! );
+//!
+//! // This trace is written no matter what.
+//! let span = info_span!("health_check", trace_id = %id, force_log = true);
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::time::SystemTime;
+use tracing::Metadata;
+
+use super::Fields;
+
+/// How much of each kind of trace to write.
+pub struct HeadSampling {
+    default_rate: f64,
+    rates: HashMap<String, f64>,
+    trace_id_field: String,
+    force_field: String,
+}
+
+/// The decision for a trace, kept in the extensions of each of its spans.
+pub(crate) struct Sampled(pub bool);
+
+impl HeadSampling {
+    /// Write a fraction (from `0.0` to `1.0`) of traces. The trace id is read from the root span's
+    /// `trace_id` field, and a root span with `force_log = true` is always written.
+    pub fn new(default_rate: f64) -> Self {
+        HeadSampling {
+            default_rate,
+            rates: HashMap::new(),
+            trace_id_field: String::from("trace_id"),
+            force_field: String::from("force_log"),
+        }
+    }
+
+    /// Write a different fraction of the traces whose root span has this name.
+    pub fn with_rate(mut self, root_name: impl Into<String>, rate: f64) -> Self {
+        self.rates.insert(root_name.into(), rate);
+        self
+    }
+
+    /// Read the trace id from a different field of the root span.
+    pub fn with_trace_id_field(mut self, trace_id_field: impl Into<String>) -> Self {
+        self.trace_id_field = trace_id_field.into();
+        self
+    }
+
+    /// Always write the trace if the root span has this field set to `true`.
+    pub fn with_force_field(mut self, force_field: impl Into<String>) -> Self {
+        self.force_field = force_field.into();
+        self
+    }
+
+    /// Decide whether to write a trace, from its root span.
+    pub(crate) fn sample(&self, metadata: &Metadata<'_>, fields: &Fields) -> Sampled {
+        if fields.get(self.force_field.as_str()) == Some(&serde_json::Value::Bool(true)) {
+            return Sampled(true);
+        }
+
+        let rate = self
+            .rates
+            .get(metadata.name())
+            .copied()
+            .unwrap_or(self.default_rate);
+        if rate >= 1.0 {
+            return Sampled(true);
+        }
+        if rate <= 0.0 {
+            return Sampled(false);
+        }
+
+        let hash = match fields.get(self.trace_id_field.as_str()) {
+            Some(serde_json::Value::String(trace_id)) => fnv1a(trace_id.as_bytes()),
+            Some(trace_id) => fnv1a(trace_id.to_string().as_bytes()),
+            None => RandomState::new().hash_one(SystemTime::now()),
+        };
+        Sampled((hash as f64 / u64::MAX as f64) < rate)
+    }
+}
+
+/// A hash that's the same in every process and every version of Rust, unlike `DefaultHasher`, so
+/// that services agree on which traces to keep.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeadSampling;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    const TRACE_IDS: [&str; 20] = [
+        "4bf92f35", "00f067aa", "a3ce929d", "0e0e4736", "b7ad6b71", "69a7c1f5", "5b8aa5a2",
+        "d2b3d6c1", "1f9c2c3d", "8e7d6c5b", "3c4d5e6f", "7a8b9c0d", "e1f2a3b4", "c5d6e7f8",
+        "9a0b1c2d", "2e3f4a5b", "6c7d8e9f", "f0e1d2c3", "b4a59687", "7869f0e1",
+    ];
+
+    /// The messages written with some head sampling.
+    fn written(head_sampling: HeadSampling, f: impl FnOnce()) -> Vec<String> {
+        let layer = CustomJsonLayer::default().with_head_sampling(head_sampling);
+        capture(layer, f)
+            .json()
+            .iter()
+            .map(|line| line["message"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn makes_the_same_decision_for_the_same_trace_id() {
+        let written = written(HeadSampling::new(0.5), || {
+            for _ in 0..2 {
+                for trace_id in TRACE_IDS {
+                    let _span = tracing::info_span!("request", trace_id).entered();
+                    tracing::info!(message = trace_id);
+                }
+            }
+        });
+        let (first, second) = written.split_at(written.len() / 2);
+        assert_eq!(first, second);
+        assert!(!first.is_empty() && first.len() < TRACE_IDS.len());
+    }
+
+    #[test]
+    fn writes_whole_traces_or_nothing() {
+        let written = written(HeadSampling::new(0.5), || {
+            for trace_id in TRACE_IDS {
+                let _span = tracing::info_span!("request", trace_id).entered();
+                tracing::info!(message = trace_id);
+                let _child = tracing::info_span!("query").entered();
+                tracing::info!(message = trace_id);
+                let _grandchild = tracing::info_span!("fetch").entered();
+                tracing::info!(message = trace_id);
+            }
+        });
+        let kept: Vec<_> = written.iter().step_by(3).collect();
+        let expected: Vec<_> = kept
+            .iter()
+            .flat_map(|trace_id| [trace_id.as_str(); 3])
+            .collect();
+        assert_eq!(written, expected);
+        assert!(!kept.is_empty() && kept.len() < TRACE_IDS.len());
+    }
+
+    #[test]
+    fn uses_the_rate_for_the_root_span_name() {
+        let head_sampling = HeadSampling::new(0.0)
+            .with_rate("checkout", 1.0)
+            .with_rate("health_check", 0.0);
+        let written = written(head_sampling, || {
+            {
+                let _span = tracing::info_span!("browse").entered();
+                tracing::info!("browse");
+            }
+            {
+                // Only the root span's name counts.
+                let _span = tracing::info_span!("checkout").entered();
+                let _child = tracing::info_span!("health_check").entered();
+                tracing::info!("checkout");
+            }
+            {
+                let _span = tracing::info_span!("health_check").entered();
+                tracing::info!("health_check");
+            }
+            tracing::info!("outside of any span");
+        });
+        assert_eq!(written, ["checkout", "outside of any span"]);
+    }
+
+    #[test]
+    fn reads_the_trace_id_and_force_fields_it_is_told_to() {
+        let head_sampling = HeadSampling::new(0.5)
+            .with_trace_id_field("correlation_id")
+            .with_force_field("debug");
+        let written = written(head_sampling, || {
+            for trace_id in ["a", "b"] {
+                for correlation_id in TRACE_IDS {
+                    let _span = tracing::info_span!("request", trace_id, correlation_id).entered();
+                    tracing::info!(message = correlation_id);
+                }
+            }
+            let _forced = tracing::info_span!("request", debug = true).entered();
+            tracing::info!("forced");
+        });
+        let (forced, written) = written.split_last().unwrap();
+        assert_eq!(forced, "forced");
+        let (first, second) = written.split_at(written.len() / 2);
+        assert_eq!(first, second);
+    }
+}
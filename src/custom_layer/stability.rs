@@ -0,0 +1,404 @@
+//! Keep the JSON type of each field stable, so that log stores that fix a field's type the first
+//! time they see it (like Elasticsearch) don't reject later documents.
+//!
+//! The first type seen for each field path (like `fields.json.age`) is remembered, either across
+//! every callsite or separately for each one. Later values of a different type are dealt with
+//! according to a `TypeMismatch` policy. A `null` never sets or breaks a field's type, and an
+//! integer that `with_large_integer_limit` wrote as a string still counts as a number.
+//!
+//! At most `MAX_PATHS` field paths are remembered, so that maps keyed by IDs can't make the table
+//! grow forever. Once it's full, paths that haven't been seen yet aren't checked, and a warning is
+//! added to the first event that couldn't be.
+
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{RwLock, RwLockReadGuard};
+use tracing::callsite::Identifier;
+
+use super::numeric::Stringified;
+use super::Fields;
+
+/// Whether field types are remembered for every callsite together, or for each one separately.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TypeScope {
+    /// The same field path must have the same type everywhere.
+    #[default]
+    Global,
+    /// Each callsite (each event or span macro) can have its own type for a field path.
+    Callsite,
+}
+
+/// What to do with a value whose type doesn't match the first one seen for its field.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TypeMismatch {
+    /// Convert the value to the expected type (e.g. `200` to `"200"`), if it can be. If it can't,
+    /// it's moved to a type-suffixed key and a warning is added to the event.
+    #[default]
+    Coerce,
+    /// Move the value to a key with the type as a suffix, like `status_string`.
+    Suffix,
+    /// Write the value as it is, but add a warning to the event.
+    Report,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Bool,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl Kind {
+    fn of(value: &Value, stringified: &Stringified) -> Option<Kind> {
+        match value {
+            Value::Null => None,
+            Value::Bool(_) => Some(Kind::Bool),
+            Value::Number(_) => Some(Kind::Number),
+            // It was a number before the numeric policy made it a string, so converting it back
+            // would undo the policy.
+            Value::String(s) if stringified.contains(s) => Some(Kind::Number),
+            Value::String(_) => Some(Kind::String),
+            Value::Array(_) => Some(Kind::Array),
+            Value::Object(_) => Some(Kind::Object),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Bool => "bool",
+            Kind::Number => "number",
+            Kind::String => "string",
+            Kind::Array => "array",
+            Kind::Object => "object",
+        }
+    }
+}
+
+/// The most field paths (across every callsite) whose types are remembered.
+const MAX_PATHS: usize = 10_000;
+
+type Seen = HashMap<(Option<Identifier>, String), Kind>;
+
+pub(crate) struct TypeStability {
+    scope: TypeScope,
+    mismatch: TypeMismatch,
+    /// Almost every check only reads this, so it's only locked for writing to add new paths.
+    seen: RwLock<Seen>,
+    /// Whether the warning about `seen` being full has been given.
+    warned_full: AtomicBool,
+}
+
+/// What to do with a value once it's been checked.
+enum Checked {
+    Keep,
+    /// Move the value to a key with this suffix.
+    Move(Kind),
+}
+
+impl TypeStability {
+    pub fn new(scope: TypeScope, mismatch: TypeMismatch) -> Self {
+        TypeStability {
+            scope,
+            mismatch,
+            seen: RwLock::new(HashMap::new()),
+            warned_full: AtomicBool::new(false),
+        }
+    }
+
+    /// Check the values in `fields`, whose paths start with `prefix`, for the callsite they were
+    /// recorded at. Anything worth knowing about is added to `warnings`.
+    pub fn check(
+        &self,
+        callsite: Identifier,
+        prefix: &str,
+        fields: &mut Fields,
+        stringified: &Stringified,
+        warnings: &mut Vec<String>,
+    ) {
+        let callsite = match self.scope {
+            TypeScope::Global => None,
+            TypeScope::Callsite => Some(callsite),
+        };
+        let mut checker = Checker {
+            stability: self,
+            callsite,
+            seen: match self.seen.read() {
+                Ok(seen) => seen,
+                Err(poisoned) => poisoned.into_inner(),
+            },
+            new: HashMap::new(),
+            stringified,
+            warnings,
+        };
+
+        let mut moved = Vec::new();
+        for (key, value) in fields.iter_mut() {
+            let path = format!("{}.{}", prefix, key);
+            if let Checked::Move(kind) = checker.check_value(path, value) {
+                moved.push((key.clone(), kind));
+            }
+        }
+        for (key, kind) in moved {
+            if let Some(value) = fields.shift_remove(&key) {
+                fields.insert(format!("{}_{}", key, kind.name()).into(), value);
+            }
+        }
+
+        let Checker {
+            seen,
+            new,
+            warnings,
+            ..
+        } = checker;
+        drop(seen);
+        self.remember(new, warnings);
+    }
+
+    /// Remember the types of paths seen for the first time, as long as there's room.
+    fn remember(&self, new: Seen, warnings: &mut Vec<String>) {
+        if new.is_empty() {
+            return;
+        }
+        let mut seen = match self.seen.write() {
+            Ok(seen) => seen,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for (key, kind) in new {
+            if seen.len() >= MAX_PATHS {
+                if !self.warned_full.swap(true, Ordering::Relaxed) {
+                    warnings.push(format!(
+                        "type stability is remembering {} field paths, so new ones won't be checked",
+                        MAX_PATHS
+                    ));
+                }
+                return;
+            }
+            // Another event may have seen the path first, in which case its type wins.
+            seen.entry(key).or_insert(kind);
+        }
+    }
+}
+
+struct Checker<'a> {
+    stability: &'a TypeStability,
+    callsite: Option<Identifier>,
+    seen: RwLockReadGuard<'a, Seen>,
+    /// Paths seen for the first time, to be remembered once the check is done.
+    new: Seen,
+    stringified: &'a Stringified,
+    warnings: &'a mut Vec<String>,
+}
+
+impl Checker<'_> {
+    fn check_value(&mut self, path: String, value: &mut Value) -> Checked {
+        let kind = match Kind::of(value, self.stringified) {
+            Some(kind) => kind,
+            None => return Checked::Keep,
+        };
+        let key = (self.callsite.clone(), path);
+        let expected = match self.seen.get(&key) {
+            Some(expected) => *expected,
+            None => *self.new.entry(key.clone()).or_insert(kind),
+        };
+        let path = key.1;
+
+        if kind != expected {
+            match self.stability.mismatch {
+                TypeMismatch::Coerce => match coerce(value, expected) {
+                    Some(coerced) => *value = coerced,
+                    None => {
+                        self.warnings.push(format!(
+                            "{} was a {} but is usually a {}, and couldn't be converted",
+                            path,
+                            kind.name(),
+                            expected.name()
+                        ));
+                        return Checked::Move(kind);
+                    }
+                },
+                TypeMismatch::Suffix => return Checked::Move(kind),
+                TypeMismatch::Report => {
+                    self.warnings.push(format!(
+                        "{} was a {} but is usually a {}",
+                        path,
+                        kind.name(),
+                        expected.name()
+                    ));
+                    return Checked::Keep;
+                }
+            }
+        }
+
+        match value {
+            Value::Object(object) => self.check_object(&path, object),
+            Value::Array(array) => {
+                let path = format!("{}[]", path);
+                for value in array {
+                    // There's no key to move an array element to, so it's left as it is.
+                    self.check_value(path.clone(), value);
+                }
+            }
+            _ => {}
+        }
+        Checked::Keep
+    }
+
+    fn check_object(&mut self, path: &str, object: &mut Map<String, Value>) {
+        let mut moved = Vec::new();
+        for (key, value) in object.iter_mut() {
+            let path = format!("{}.{}", path, key);
+            if let Checked::Move(kind) = self.check_value(path, value) {
+                moved.push((key.clone(), kind));
+            }
+        }
+        for (key, kind) in moved {
+            if let Some(value) = object.remove(&key) {
+                object.insert(format!("{}_{}", key, kind.name()), value);
+            }
+        }
+    }
+}
+
+/// Convert a value to another type, if there's a sensible way to.
+fn coerce(value: &Value, kind: Kind) -> Option<Value> {
+    match (kind, value) {
+        (Kind::String, Value::String(_)) => Some(value.clone()),
+        (Kind::String, _) => Some(Value::String(value.to_string())),
+        (Kind::Number, Value::String(s)) => s
+            .trim()
+            .parse::<serde_json::Number>()
+            .ok()
+            .map(Value::Number),
+        (Kind::Bool, Value::String(s)) => match s.as_str() {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        (Kind::Array, value) => Some(Value::Array(vec![value.clone()])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{TypeMismatch, TypeScope, MAX_PATHS};
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::{CustomJsonLayer, MAX_SAFE_INTEGER};
+    use crate::macros::tracing_json_old_helper;
+
+    #[test]
+    fn coerces_to_the_first_type_seen() {
+        let layer =
+            CustomJsonLayer::default().with_type_stability(TypeScope::Global, TypeMismatch::Coerce);
+        let captured = capture(layer, || {
+            tracing::info!(status = "ok");
+            tracing::info!(status = 200);
+        });
+        assert_eq!(captured.json()[1]["fields"]["status"], "200");
+    }
+
+    #[test]
+    fn reports_mismatches_without_changing_them() {
+        let layer =
+            CustomJsonLayer::default().with_type_stability(TypeScope::Global, TypeMismatch::Report);
+        let captured = capture(layer, || {
+            tracing::info!(status = "ok");
+            tracing::info!(status = 200);
+        });
+        let line = &captured.json()[1];
+        assert_eq!(line["fields"], json!({ "status": 200 }));
+        assert_eq!(
+            line["warnings"],
+            json!(["fields.status was a number but is usually a string"])
+        );
+    }
+
+    #[test]
+    fn leaves_large_integers_as_strings() {
+        for mismatch in [TypeMismatch::Coerce, TypeMismatch::Suffix] {
+            let layer = CustomJsonLayer::default()
+                .with_large_integer_limit(MAX_SAFE_INTEGER)
+                .with_type_stability(TypeScope::Global, mismatch);
+            let captured = capture(layer, || {
+                tracing::info!(big = 1u64);
+                tracing::info!(big = u64::MAX - 1);
+                tracing::info!(big = i128::MIN);
+            });
+            let lines = captured.json();
+
+            assert_eq!(lines[1]["fields"], json!({ "big": "18446744073709551614" }));
+            assert_eq!(
+                lines[2]["fields"],
+                json!({ "big": "-170141183460469231731687303715884105728" })
+            );
+            assert!(lines.iter().all(|line| line.get("warnings").is_none()));
+        }
+    }
+
+    #[test]
+    fn still_coerces_other_strings_to_numbers() {
+        let layer = CustomJsonLayer::default()
+            .with_large_integer_limit(MAX_SAFE_INTEGER)
+            .with_type_stability(TypeScope::Global, TypeMismatch::Coerce);
+        let captured = capture(layer, || {
+            tracing::info!(n = 1u64);
+            tracing::info!(n = "42");
+        });
+        assert_eq!(captured.json()[1]["fields"]["n"], 42);
+    }
+
+    #[test]
+    fn moves_digit_strings_that_were_logged_as_strings() {
+        let layer =
+            CustomJsonLayer::default().with_type_stability(TypeScope::Global, TypeMismatch::Suffix);
+        let captured = capture(layer, || {
+            tracing::info!(order = "abc");
+            tracing::info!(order = "123456789012345678901234567890");
+            tracing::info!(n = 1);
+            tracing::info!(n = "340282366920938463463374607431768211456");
+        });
+        let lines = captured.json();
+
+        assert_eq!(
+            lines[1]["fields"],
+            json!({ "order": "123456789012345678901234567890" })
+        );
+        assert_eq!(
+            lines[3]["fields"],
+            json!({ "n_string": "340282366920938463463374607431768211456" })
+        );
+    }
+
+    #[test]
+    fn stops_remembering_paths_when_full() {
+        let layer =
+            CustomJsonLayer::default().with_type_stability(TypeScope::Global, TypeMismatch::Suffix);
+        let by_id: serde_json::Map<_, _> = (1..MAX_PATHS)
+            .map(|id| (id.to_string(), json!(id)))
+            .collect();
+        let by_id = tracing_json_old_helper(&by_id);
+        let captured = capture(layer, || {
+            tracing::info!(by_id = by_id.as_str());
+            tracing::info!(late = 1);
+            tracing::info!(late = "a");
+            tracing::info!(later = 1);
+        });
+        let lines = captured.json();
+
+        // `by_id` and its keys fill the table, so `late` doesn't make it in.
+        assert_eq!(
+            lines[1]["warnings"],
+            json!([format!(
+                "type stability is remembering {} field paths, so new ones won't be checked",
+                MAX_PATHS
+            )])
+        );
+        assert_eq!(lines[2]["fields"]["late"], "a");
+        assert!(lines[3].get("warnings").is_none());
+    }
+}
@@ -0,0 +1,214 @@
+//! Tail-based sampling: hold on to every event of a root span until it closes, and only then
+//! decide whether to write them. Spans that had an ERROR or were slow are always written, and a
+//! fraction of the rest are.
+//!
+//! The events are kept, already serialized, in a `TailBuffer` in the root span's extensions, next
+//! to its `CustomLayerTracedData`. Events outside of any span are written straight away.
+//!
+//! ```no_compile
+//! let layer = CustomJsonLayer::default().with_tail_sampling(
+//!     TailSampling::new(0.01).with_latency_threshold(Duration::from_millis(500)),
+//! );
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{Level, Metadata};
+
+/// When to write the events of a root span.
+pub struct TailSampling {
+    sample_rate: f64,
+    latency_threshold: Option<Duration>,
+    span_cap: usize,
+    global_cap: usize,
+    /// How many bytes are held across every buffer right now.
+    buffered: AtomicUsize,
+}
+
+/// The events of a root span (and every span inside it) that are waiting for it to close.
+pub(crate) struct TailBuffer {
+    started: Instant,
+    lines: Vec<(&'static Metadata<'static>, Vec<u8>)>,
+    bytes: usize,
+    errored: bool,
+}
+
+impl TailSampling {
+    /// Write all the events of a fraction (from `0.0` to `1.0`) of the root spans that didn't have
+    /// an error. By default each span holds at most 1 MiB of events, and all of them together at
+    /// most 64 MiB.
+    pub fn new(sample_rate: f64) -> Self {
+        TailSampling {
+            sample_rate,
+            latency_threshold: None,
+            span_cap: 1 << 20,
+            global_cap: 64 << 20,
+            buffered: AtomicUsize::new(0),
+        }
+    }
+
+    /// Always write the events of root spans that took at least this long.
+    pub fn with_latency_threshold(mut self, latency_threshold: Duration) -> Self {
+        self.latency_threshold = Some(latency_threshold);
+        self
+    }
+
+    /// The most bytes of events a single root span can hold. Once a span is full, its events are
+    /// dropped. An ERROR event that's dropped still makes the span's other events get written.
+    pub fn with_span_cap(mut self, span_cap: usize) -> Self {
+        self.span_cap = span_cap;
+        self
+    }
+
+    /// The most bytes of events all root spans together can hold. Once that's reached, events are
+    /// dropped, the same way as when a span is full.
+    pub fn with_global_cap(mut self, global_cap: usize) -> Self {
+        self.global_cap = global_cap;
+        self
+    }
+
+    pub(crate) fn start(&self) -> TailBuffer {
+        TailBuffer {
+            started: Instant::now(),
+            lines: Vec::new(),
+            bytes: 0,
+            errored: false,
+        }
+    }
+
+    /// Add an event to a span's buffer, if there's room.
+    pub(crate) fn buffer(
+        &self,
+        buffer: &mut TailBuffer,
+        metadata: &'static Metadata<'static>,
+        line: Vec<u8>,
+    ) {
+        // An error decides whether the span is written, even if there's no room left for it.
+        buffer.errored |= *metadata.level() == Level::ERROR;
+
+        let len = line.len();
+        if buffer.bytes + len > self.span_cap {
+            return;
+        }
+        // Check the global cap and take the room in one go, so that threads buffering at the same
+        // time can't all see room for their event and go over the cap together.
+        let reserved = self
+            .buffered
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+                total
+                    .checked_add(len)
+                    .filter(|&total| total <= self.global_cap)
+            });
+        if reserved.is_err() {
+            return;
+        }
+        buffer.bytes += len;
+        buffer.lines.push((metadata, line));
+    }
+
+    /// The span has closed: return its events if they should be written.
+    pub(crate) fn finish(
+        &self,
+        buffer: TailBuffer,
+    ) -> Option<Vec<(&'static Metadata<'static>, Vec<u8>)>> {
+        self.buffered.fetch_sub(buffer.bytes, Ordering::Relaxed);
+        let slow = self
+            .latency_threshold
+            .is_some_and(|threshold| buffer.started.elapsed() >= threshold);
+        if buffer.errored || slow || self.sampled() {
+            Some(buffer.lines)
+        } else {
+            None
+        }
+    }
+
+    fn sampled(&self) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        if self.sample_rate <= 0.0 {
+            return false;
+        }
+        // Randomly keyed hashers are a good enough source of randomness for sampling.
+        let random = RandomState::new().hash_one(SystemTime::now());
+        (random as f64 / u64::MAX as f64) < self.sample_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use tracing::{Callsite, Level};
+
+    use super::TailSampling;
+
+    fn info_metadata() -> &'static tracing::Metadata<'static> {
+        tracing::callsite! {
+            name: "buffered",
+            kind: tracing::metadata::Kind::EVENT,
+            level: Level::INFO,
+            fields: []
+        }
+        .metadata()
+    }
+
+    fn error_metadata() -> &'static tracing::Metadata<'static> {
+        tracing::callsite! {
+            name: "failed",
+            kind: tracing::metadata::Kind::EVENT,
+            level: Level::ERROR,
+            fields: []
+        }
+        .metadata()
+    }
+
+    #[test]
+    fn caps_errors_but_still_writes_the_span() {
+        let tail = TailSampling::new(0.0)
+            .with_span_cap(100)
+            .with_global_cap(150);
+        let mut full = tail.start();
+        tail.buffer(&mut full, info_metadata(), vec![b'a'; 100]);
+        tail.buffer(&mut full, error_metadata(), vec![b'e'; 10]);
+        assert_eq!(full.bytes, 100);
+
+        // Another span only has room for 50 more bytes between them.
+        let mut other = tail.start();
+        for _ in 0..10 {
+            tail.buffer(&mut other, error_metadata(), vec![b'e'; 10]);
+        }
+        assert_eq!(other.bytes, 50);
+        assert_eq!(tail.buffered.load(Ordering::Relaxed), 150);
+
+        let lines = tail.finish(full).unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(tail.finish(other).unwrap().len(), 5);
+        assert_eq!(tail.buffered.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn stays_under_the_global_cap_across_threads() {
+        let tail = TailSampling::new(1.0).with_global_cap(1000);
+        let held: usize = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut buffer = tail.start();
+                        for _ in 0..100 {
+                            tail.buffer(&mut buffer, info_metadata(), vec![b'a'; 10]);
+                        }
+                        buffer.bytes
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .sum()
+        });
+        assert_eq!(held, 1000);
+        assert_eq!(tail.buffered.load(Ordering::Relaxed), 1000);
+    }
+}
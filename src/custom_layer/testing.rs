@@ -0,0 +1,58 @@
+//! Helpers for the layer's tests: run some code with a layer as the default subscriber and look at
+//! the lines it wrote.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+
+use super::CustomJsonLayer;
+
+/// Everything a layer wrote.
+#[derive(Clone, Default)]
+pub(crate) struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    pub fn lines(&self) -> Vec<String> {
+        let bytes = self.0.lock().unwrap();
+        String::from_utf8_lossy(&bytes)
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    pub fn json(&self) -> Vec<serde_json::Value> {
+        self.lines()
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Captured;
+
+    fn make_writer(&'a self) -> Captured {
+        self.clone()
+    }
+}
+
+/// Run `f` with `layer` as the default subscriber. The subscriber (and the layer) are dropped
+/// before this returns, so whatever the layer writes on drop is captured too.
+pub(crate) fn capture(layer: CustomJsonLayer, f: impl FnOnce()) -> Captured {
+    let captured = Captured::default();
+    let subscriber = tracing_subscriber::registry().with(layer.with_writer(captured.clone()));
+    tracing::subscriber::with_default(subscriber, f);
+    captured
+}
@@ -0,0 +1,171 @@
+//! Convert `valuable` values to JSON, following the layer's `NumericPolicy`.
+//!
+//! This produces the same shapes as `valuable_serde` does with `serde_json`, but going through
+//! serde would turn non-finite floats into `null` before the policy got a look at them.
+//!
+//! The one difference is dynamic enums (like the ones `SerdeAdapter` produces), which
+//! `valuable_serde` writes without their variant. Here they're tagged with it, like serde does.
+
+use serde_json::{Map, Value as Json};
+use valuable::{
+    EnumDef, Fields, NamedValues, StructDef, TupleDef, Valuable, Value, Variant, Visit,
+};
+
+use super::numeric::{NumericPolicy, Stringified};
+
+pub(crate) struct ToJson<'a> {
+    numbers: &'a NumericPolicy,
+    /// Set if a non-finite float was written in a way that should be warned about.
+    pub non_finite: bool,
+    /// The integers the numeric policy wrote as strings.
+    pub stringified: Stringified,
+}
+
+impl<'a> ToJson<'a> {
+    pub fn new(numbers: &'a NumericPolicy) -> Self {
+        ToJson {
+            numbers,
+            non_finite: false,
+            stringified: Stringified::new(),
+        }
+    }
+
+    pub fn convert(&mut self, value: Value<'_>) -> Json {
+        match value {
+            Value::Bool(b) => Json::Bool(b),
+            Value::I8(n) => self.numbers.i64(n.into(), &mut self.stringified),
+            Value::I16(n) => self.numbers.i64(n.into(), &mut self.stringified),
+            Value::I32(n) => self.numbers.i64(n.into(), &mut self.stringified),
+            Value::I64(n) => self.numbers.i64(n, &mut self.stringified),
+            Value::Isize(n) => self.numbers.i64(n as i64, &mut self.stringified),
+            Value::I128(n) => self.numbers.i128(n, &mut self.stringified),
+            Value::U8(n) => self.numbers.u64(n.into(), &mut self.stringified),
+            Value::U16(n) => self.numbers.u64(n.into(), &mut self.stringified),
+            Value::U32(n) => self.numbers.u64(n.into(), &mut self.stringified),
+            Value::U64(n) => self.numbers.u64(n, &mut self.stringified),
+            Value::Usize(n) => self.numbers.u64(n as u64, &mut self.stringified),
+            Value::U128(n) => self.numbers.u128(n, &mut self.stringified),
+            Value::F32(n) => self.f64(n.into()),
+            Value::F64(n) => self.f64(n),
+            Value::Char(c) => Json::String(c.to_string()),
+            Value::String(s) => Json::String(s.to_owned()),
+            Value::Path(p) => Json::String(p.to_string_lossy().into_owned()),
+            Value::Error(e) => Json::String(e.to_string()),
+            Value::Unit => Json::Null,
+            Value::Listable(list) => Json::Array(self.collect(list).values),
+            Value::Mappable(map) => Json::Object(self.collect(map).entries),
+            Value::Structable(structable) => {
+                let collected = self.collect(structable);
+                match structable.definition() {
+                    StructDef::Static {
+                        fields: Fields::Named(_),
+                        ..
+                    } => Json::Object(collected.entries),
+                    StructDef::Static { .. } => collected.newtype_or_array(),
+                    def if def.fields().is_named() => Json::Object(collected.entries),
+                    _ => Json::Array(collected.values),
+                }
+            }
+            Value::Enumerable(enumerable) => {
+                let collected = self.collect(enumerable);
+                let variant = enumerable.variant();
+                match (enumerable.definition(), &variant) {
+                    (EnumDef::Static { .. }, Variant::Static(def)) => {
+                        let body = if variant.is_named_fields() {
+                            Json::Object(collected.entries)
+                        } else {
+                            collected.newtype_or_array()
+                        };
+                        Json::Object(Map::from_iter([(def.name().to_owned(), body)]))
+                    }
+                    (EnumDef::Dynamic { .. }, _) => {
+                        let body = if variant.is_named_fields() {
+                            Json::Object(collected.entries)
+                        } else if collected.values.is_empty() {
+                            return Json::String(variant.name().to_owned());
+                        } else {
+                            collected.newtype_or_array()
+                        };
+                        Json::Object(Map::from_iter([(variant.name().to_owned(), body)]))
+                    }
+                    _ if variant.is_named_fields() => Json::Object(collected.entries),
+                    _ => Json::Array(collected.values),
+                }
+            }
+            Value::Tuplable(tuplable) => match tuplable.definition() {
+                TupleDef::Static { fields: 0, .. } => Json::Null,
+                _ => Json::Array(self.collect(tuplable).values),
+            },
+            _ => Json::Null,
+        }
+    }
+
+    fn f64(&mut self, value: f64) -> Json {
+        let (json, warn) = self.numbers.f64(value);
+        self.non_finite |= warn;
+        json
+    }
+
+    fn collect(&mut self, valuable: &dyn Valuable) -> Collected {
+        let mut collector = Collector {
+            to_json: self,
+            collected: Collected::default(),
+        };
+        valuable.visit(&mut collector);
+        collector.collected
+    }
+}
+
+/// Whatever a `Valuable` handed to its visitor.
+#[derive(Default)]
+struct Collected {
+    values: Vec<Json>,
+    entries: Map<String, Json>,
+}
+
+impl Collected {
+    /// A single unnamed field is written as just that field, like serde's newtypes.
+    fn newtype_or_array(mut self) -> Json {
+        if self.values.len() == 1 {
+            self.values.remove(0)
+        } else {
+            Json::Array(self.values)
+        }
+    }
+}
+
+struct Collector<'a, 'b> {
+    to_json: &'a mut ToJson<'b>,
+    collected: Collected,
+}
+
+impl Visit for Collector<'_, '_> {
+    fn visit_value(&mut self, value: Value<'_>) {
+        let value = self.to_json.convert(value);
+        self.collected.values.push(value);
+    }
+
+    fn visit_entry(&mut self, key: Value<'_>, value: Value<'_>) {
+        let key = match self.to_json.convert(key) {
+            Json::String(key) => key,
+            key => key.to_string(),
+        };
+        let value = self.to_json.convert(value);
+        self.collected.entries.insert(key, value);
+    }
+
+    fn visit_named_fields(&mut self, named_values: &NamedValues<'_>) {
+        for (field, value) in named_values {
+            let value = self.to_json.convert(*value);
+            self.collected
+                .entries
+                .insert(field.name().to_owned(), value);
+        }
+    }
+
+    fn visit_unnamed_fields(&mut self, values: &[Value<'_>]) {
+        for value in values {
+            self.visit_value(*value);
+        }
+    }
+}
@@ -0,0 +1,509 @@
+//! Parse the output of `#[derive(Debug)]` into JSON, for types that don't implement anything
+//! better.
+//!
+//! ```text
+//! Config { port: 80, hosts: ["a", "b"], mode: Fast, retry: Some(3) }
+//! ```
+//!
+//! becomes
+//!
+//! ```json
+//! { "port": 80, "hosts": ["a", "b"], "mode": "Fast", "retry": 3 }
+//! ```
+//!
+//! The mapping is close to what serde would do with the same types, but Debug output doesn't say
+//! whether a name is a struct or an enum variant, so they're all treated alike:
+//!
+//! * Structs and struct variants become objects of their fields (the name is dropped, where serde
+//!   would keep a variant's), and a trailing `..` from `finish_non_exhaustive` is ignored.
+//! * `Name(a)` becomes `{ "Name": a }` and `Name(a, b)` becomes `{ "Name": [a, b] }`, like serde
+//!   does for variants, even if `Name` is a tuple struct (which serde would write as just `a` or
+//!   `[a, b]`). `Some(a)` is just `a`. A bare `Name` becomes the string `"Name"`, and `None` is
+//!   `null`.
+//! * Lists and tuples become arrays, sets become arrays, maps become objects (keys that aren't
+//!   strings are written as JSON), and `()` is `null`.
+//! * Strings and chars become strings, and numbers and booleans stay as they are. Integers that
+//!   don't fit in 64 bits, `NaN`, and `inf` become strings.
+//!
+//! Both `{:?}` and `{:#?}` output are understood. Anything else is an error, so hand-written
+//! `Debug` impls that don't look like a derived one can be left as they were.
+//!
+//! Like `filter_expr`, nothing in here knows about the layer, which lets the fuzz target in
+//! `fuzz/` include it directly.
+
+use serde_json::{Map, Number, Value};
+use std::fmt;
+
+/// How deep values can be nested before parsing gives up, so that deeply nested input can't blow
+/// the stack.
+const MAX_DEPTH: usize = 128;
+
+/// Parse Debug output into JSON.
+pub fn parse(source: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser {
+        source,
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.position < source.len() {
+        return Err(parser.error("expected the end of the input"));
+    }
+    Ok(value)
+}
+
+/// An error from parsing Debug output.
+#[derive(Debug)]
+pub struct ParseError {
+    message: &'static str,
+    position: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Parser<'a> {
+    source: &'a str,
+    /// A byte offset into `source`, always on a char boundary.
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            message,
+            position: self.position,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.position..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skip whitespace, then consume `c` if it's next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.position += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char, message: &'static str) -> Result<(), ParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        self.skip_whitespace();
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("values are nested too deeply"));
+        }
+        self.depth += 1;
+        let value = self.parse_value_inner();
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_value_inner(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => self.parse_string('"').map(Value::String),
+            Some('\'') => self.parse_string('\'').map(Value::String),
+            Some('[') => {
+                self.bump();
+                self.parse_sequence(']').map(Value::Array)
+            }
+            Some('(') => {
+                self.bump();
+                let values = self.parse_sequence(')')?;
+                Ok(if values.is_empty() {
+                    Value::Null
+                } else {
+                    Value::Array(values)
+                })
+            }
+            Some('{') => {
+                self.bump();
+                self.parse_map_or_set()
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(c) if c.is_alphabetic() || c == '_' => self.parse_named(),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("expected a value, found the end of the input")),
+        }
+    }
+
+    /// A comma-separated list of values, allowing a trailing comma, up to `close`. The opening
+    /// bracket has already been consumed.
+    fn parse_sequence(&mut self, close: char) -> Result<Vec<Value>, ParseError> {
+        let mut values = Vec::new();
+        loop {
+            if self.eat(close) {
+                return Ok(values);
+            }
+            values.push(self.parse_value()?);
+            if !self.eat(',') {
+                self.expect(close, "expected `,` or a closing bracket")?;
+                return Ok(values);
+            }
+        }
+    }
+
+    /// `{ key: value, ... }` for maps, or `{ value, ... }` for sets. The `{` has already been
+    /// consumed.
+    fn parse_map_or_set(&mut self) -> Result<Value, ParseError> {
+        if self.eat('}') {
+            return Ok(Value::Object(Map::new()));
+        }
+        let first = self.parse_value()?;
+        if !self.eat(':') {
+            let mut values = vec![first];
+            if self.eat(',') {
+                values.extend(self.parse_sequence('}')?);
+            } else {
+                self.expect('}', "expected `,` or `}`")?;
+            }
+            return Ok(Value::Array(values));
+        }
+
+        let mut map = Map::new();
+        let mut key = first;
+        loop {
+            let value = self.parse_value()?;
+            map.insert(key_string(key), value);
+            if !self.eat(',') {
+                self.expect('}', "expected `,` or `}`")?;
+                return Ok(Value::Object(map));
+            }
+            if self.eat('}') {
+                return Ok(Value::Object(map));
+            }
+            key = self.parse_value()?;
+            self.expect(':', "expected `:`")?;
+        }
+    }
+
+    /// Anything that starts with a name: structs, tuple structs, unit structs and variants, and
+    /// the keywords.
+    fn parse_named(&mut self) -> Result<Value, ParseError> {
+        let name = self.parse_path();
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.bump();
+                self.parse_struct_fields()
+            }
+            Some('(') => {
+                self.bump();
+                let mut values = self.parse_sequence(')')?;
+                if name == "Some" && values.len() == 1 {
+                    return Ok(values.remove(0));
+                }
+                let value = match values.len() {
+                    0 => Value::Null,
+                    1 => values.remove(0),
+                    _ => Value::Array(values),
+                };
+                Ok(Value::Object(Map::from_iter([(name.to_owned(), value)])))
+            }
+            _ => Ok(match name {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "None" => Value::Null,
+                _ => Value::String(name.to_owned()),
+            }),
+        }
+    }
+
+    /// An identifier, or a path of them separated by `::`.
+    fn parse_path(&mut self) -> &'a str {
+        let start = self.position;
+        loop {
+            while self
+                .peek()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '#')
+            {
+                self.bump();
+            }
+            let rest = self.rest();
+            if rest.starts_with("::")
+                && rest[2..].starts_with(|c: char| c.is_alphabetic() || c == '_')
+            {
+                self.position += 2;
+            } else {
+                return &self.source[start..self.position];
+            }
+        }
+    }
+
+    /// `field: value, ...` up to `}`, possibly ending with `..`. The `{` has already been
+    /// consumed.
+    fn parse_struct_fields(&mut self) -> Result<Value, ParseError> {
+        let mut fields = Map::new();
+        loop {
+            if self.eat('}') {
+                return Ok(Value::Object(fields));
+            }
+            if self.rest().starts_with("..") {
+                self.position += 2;
+                self.expect('}', "expected `}` after `..`")?;
+                return Ok(Value::Object(fields));
+            }
+            if !self.peek().is_some_and(|c| c.is_alphabetic() || c == '_') {
+                return Err(self.error("expected a field name"));
+            }
+            let name = self.parse_path();
+            self.expect(':', "expected `:` after a field name")?;
+            let value = self.parse_value()?;
+            fields.insert(name.to_owned(), value);
+            if !self.eat(',') {
+                self.expect('}', "expected `,` or `}`")?;
+                return Ok(Value::Object(fields));
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, ParseError> {
+        let start = self.position;
+        if self.rest().starts_with('-') {
+            self.bump();
+        }
+        if self.rest().starts_with("inf") {
+            self.position += 3;
+            return Ok(Value::String(self.source[start..self.position].to_owned()));
+        }
+
+        let mut is_float = false;
+        self.skip_digits();
+        if self.peek() == Some('.') {
+            is_float = true;
+            self.bump();
+            self.skip_digits();
+        }
+        if matches!(self.peek(), Some('e' | 'E')) {
+            is_float = true;
+            self.bump();
+            if matches!(self.peek(), Some('+' | '-')) {
+                self.bump();
+            }
+            self.skip_digits();
+        }
+
+        let text = &self.source[start..self.position];
+        if is_float {
+            text.parse::<f64>()
+                .ok()
+                .and_then(Number::from_f64)
+                .map(Value::Number)
+                .ok_or_else(|| self.error("expected a number"))
+        } else if let Ok(n) = text.parse::<i64>() {
+            Ok(n.into())
+        } else if let Ok(n) = text.parse::<u64>() {
+            Ok(n.into())
+        } else if text.parse::<i128>().is_ok() || text.parse::<u128>().is_ok() {
+            Ok(Value::String(text.to_owned()))
+        } else {
+            Err(self.error("expected a number"))
+        }
+    }
+
+    fn skip_digits(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+    }
+
+    /// A string or char literal, with Rust's escapes. The opening quote hasn't been consumed yet.
+    fn parse_string(&mut self, quote: char) -> Result<String, ParseError> {
+        self.bump();
+        let mut string = String::new();
+        loop {
+            match self.bump() {
+                Some(c) if c == quote => return Ok(string),
+                Some('\\') => string.push(self.parse_escape()?),
+                Some(c) => string.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char, ParseError> {
+        match self.bump() {
+            Some('n') => Ok('\n'),
+            Some('r') => Ok('\r'),
+            Some('t') => Ok('\t'),
+            Some('0') => Ok('\0'),
+            Some(c @ ('\\' | '"' | '\'')) => Ok(c),
+            Some('u') => {
+                self.expect('{', "expected `{` after `\\u`")?;
+                let start = self.position;
+                while self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                    self.bump();
+                }
+                let code = u32::from_str_radix(&self.source[start..self.position], 16).ok();
+                self.expect('}', "expected `}` after a unicode escape")?;
+                code.and_then(char::from_u32)
+                    .ok_or_else(|| self.error("invalid unicode escape"))
+            }
+            _ => Err(self.error("invalid escape")),
+        }
+    }
+}
+
+/// Map keys have to be strings in JSON, so anything else is written as JSON.
+fn key_string(key: Value) -> String {
+    match key {
+        Value::String(key) => key,
+        key => key.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    enum Mode {
+        Fast,
+        Custom(u8),
+        Pair(i32, &'static str),
+        Named { level: u8 },
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    struct Config {
+        port: u16,
+        hosts: Vec<&'static str>,
+        modes: Vec<Mode>,
+        retry: Option<u32>,
+        timeout: Option<f64>,
+        tags: BTreeSet<char>,
+        limits: BTreeMap<u8, (bool, ())>,
+    }
+
+    fn config() -> Config {
+        Config {
+            port: 80,
+            hosts: vec!["a", "b \"c\"\n"],
+            modes: vec![
+                Mode::Fast,
+                Mode::Custom(3),
+                Mode::Pair(-1, "x"),
+                Mode::Named { level: 2 },
+            ],
+            retry: Some(3),
+            timeout: None,
+            tags: ['x', '\''].into_iter().collect(),
+            limits: [(1, (true, ()))].into_iter().collect(),
+        }
+    }
+
+    fn expected() -> Value {
+        // A struct variant looks just like a struct, so its name is dropped too.
+        json!({
+            "port": 80,
+            "hosts": ["a", "b \"c\"\n"],
+            "modes": ["Fast", { "Custom": 3 }, { "Pair": [-1, "x"] }, { "level": 2 }],
+            "retry": 3,
+            "timeout": null,
+            "tags": ["'", "x"],
+            "limits": { "1": [true, null] },
+        })
+    }
+
+    #[test]
+    fn parses_derived_debug_output() {
+        assert_eq!(parse(&format!("{:?}", config())).unwrap(), expected());
+    }
+
+    #[test]
+    fn parses_pretty_debug_output() {
+        assert_eq!(parse(&format!("{:#?}", config())).unwrap(), expected());
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(parse(&format!("{:?}", -1.5e-7)).unwrap(), json!(-1.5e-7));
+        assert_eq!(parse(&format!("{:?}", u64::MAX)).unwrap(), json!(u64::MAX));
+        assert_eq!(
+            parse(&format!("{:?}", i128::MIN)).unwrap(),
+            json!(i128::MIN.to_string())
+        );
+        assert_eq!(parse(&format!("{:?}", f64::NAN)).unwrap(), json!("NaN"));
+        assert_eq!(
+            parse(&format!("{:?}", f64::NEG_INFINITY)).unwrap(),
+            json!("-inf")
+        );
+    }
+
+    #[test]
+    fn floats_round_trip_exactly() {
+        for float in [0.1 + 0.2, 1.0 / 3.0, f64::MAX, f64::MIN_POSITIVE, 5e-324] {
+            let value = parse(&format!("{:?}", float)).unwrap();
+            assert_eq!(value.as_f64(), Some(float));
+            let reparsed: Value = serde_json::from_str(&value.to_string()).unwrap();
+            assert_eq!(reparsed, value);
+        }
+    }
+
+    #[test]
+    fn parses_non_exhaustive_structs() {
+        assert_eq!(parse("Handle { id: 7, .. }").unwrap(), json!({ "id": 7 }));
+    }
+
+    #[test]
+    fn rejects_hand_written_debug_output() {
+        for source in [
+            "<opaque>",
+            "Config { port: 80",
+            "\"abc",
+            "[1, 2",
+            "Foo { a: 1 } extra",
+        ] {
+            assert!(parse(source).is_err(), "{} parsed", source);
+        }
+        assert_eq!(
+            parse("[1, 2] x").unwrap_err().to_string(),
+            "expected the end of the input at position 7"
+        );
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let deep = format!("{}{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1));
+        assert!(parse(&deep).is_err());
+        let shallow = format!("{}{}", "[".repeat(MAX_DEPTH - 1), "]".repeat(MAX_DEPTH - 1));
+        assert!(parse(&shallow).is_ok());
+    }
+}
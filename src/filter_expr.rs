@@ -0,0 +1,821 @@
+//! A small expression language for filtering log lines by their values.
+//!
+//! ```text
+//! level >= WARN || fields.user_id == "u_123" || span.request_path ~ "^/admin"
+//! ```
+//!
+//! Expressions are made of:
+//!
+//! * Paths into the JSON, like `level`, `fields.user_id`, `spans[0].name`, or
+//!   `fields."http.method"` for keys that aren't plain identifiers.
+//! * Literals: strings (`"abc"`), numbers (`42`, `-1.5`), `true`, `false`, `null`, and the level
+//!   names `TRACE`, `DEBUG`, `INFO`, `WARN`, and `ERROR`.
+//! * Comparisons: `==`, `!=`, `<`, `<=`, `>`, `>=`, and `~`/`!~` to match against a regular
+//!   expression. Level names compare by severity, so `level >= WARN` does what it looks like.
+//! * `&&`, `||`, `!`, and parentheses. A path on its own is true if it exists and isn't `null`,
+//!   `false`, `0`, or `""`.
+//!
+//! `span` is a little more forgiving than other paths: `span.name` and `span.target` are the
+//! closest span's, but any other `span.X` (or `span.fields.X`) is the field `X` of whichever span
+//! closest to the event has it.
+//!
+//! Nothing in here knows about the layer: expressions are evaluated against anything that
+//! implements `Resolve`, including a plain `serde_json::Value`, so the same filters can be used
+//! when reading logs back in. In JSON, the spans are looked for in `span`, then `spans` from the
+//! innermost, then `root_span`, and their fields can be either next to their `name` and `target`
+//! or in a `fields` object of their own. So a filter finds the same thing in a line as it did in
+//! the event, as long as the line has the span it's looking for.
+
+use regex::Regex;
+use serde_json::Value;
+use std::{borrow::Cow, cmp::Ordering, fmt, str::FromStr};
+
+/// A parsed filter expression.
+#[derive(Debug)]
+pub struct Filter(Expr);
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let tokens = lex(source)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+        };
+        let expr = parser.parse_or()?;
+        match parser.peek() {
+            (Token::End, _) => Ok(Filter(expr)),
+            (token, position) => Err(ParseError::new(
+                format!(
+                    "expected `&&`, `||`, or the end of the filter, found {}",
+                    token
+                ),
+                *position,
+            )),
+        }
+    }
+
+    /// Whether the data matches the filter.
+    pub fn matches(&self, data: &impl Resolve) -> bool {
+        self.0.evaluate(data).is_truthy()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        Filter::parse(source)
+    }
+}
+
+/// Something that filter paths can be looked up in.
+pub trait Resolve {
+    /// Look up a path, like `["fields", "user_id"]`. Returns `None` if there's nothing there.
+    fn resolve(&self, path: &[PathSegment]) -> Option<Cow<'_, Value>>;
+}
+
+impl Resolve for Value {
+    fn resolve(&self, path: &[PathSegment]) -> Option<Cow<'_, Value>> {
+        match path.split_first()? {
+            (PathSegment::Key(key), rest) if key == "span" => resolve_span_in(self, rest),
+            _ => resolve_in(self, path),
+        }
+        .map(Cow::Borrowed)
+    }
+}
+
+/// Look up a `span.` path inside a line.
+fn resolve_span_in<'a>(line: &'a Value, path: &[PathSegment]) -> Option<&'a Value> {
+    let spans = line.get("spans").and_then(Value::as_array);
+    let (key, rest) = match path {
+        [PathSegment::Key(key), rest @ ..] => (key.as_str(), rest),
+        _ => return None,
+    };
+    let (key, rest) = match (key, rest) {
+        ("name" | "target", []) => {
+            let leaf = line.get("span").or_else(|| spans?.last())?;
+            return leaf.get(key);
+        }
+        ("fields", [PathSegment::Key(key), rest @ ..]) => (key.as_str(), rest),
+        _ => (key, rest),
+    };
+
+    // From the closest span to the furthest.
+    let value = line
+        .get("span")
+        .into_iter()
+        .chain(spans.into_iter().flat_map(|spans| spans.iter().rev()))
+        .chain(line.get("root_span"))
+        .find_map(|span| match span.get("fields") {
+            Some(fields) if fields.is_object() => fields.get(key),
+            // Fields with the same name as the metadata are written with a prefix instead.
+            _ if key == "name" || key == "target" => span.get(format!("fields.{}", key).as_str()),
+            _ => span.get(key),
+        })?;
+    resolve_in(value, rest)
+}
+
+/// Look up a path inside a JSON value.
+pub fn resolve_in<'a>(mut value: &'a Value, path: &[PathSegment]) -> Option<&'a Value> {
+    for segment in path {
+        value = match segment {
+            PathSegment::Key(key) => value.get(key.as_str())?,
+            PathSegment::Index(index) => value.get(*index)?,
+        };
+    }
+    Some(value)
+}
+
+/// One step of a path: either an object key or an array index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// A filter that couldn't be parsed, with what went wrong and the byte offset in the source where
+/// it went wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    message: String,
+    position: usize,
+}
+
+impl ParseError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        ParseError {
+            message: message.into(),
+            position,
+        }
+    }
+
+    /// The source with a caret pointing at where it went wrong, for showing to a human.
+    ///
+    /// ```text
+    /// level >= WARN &&
+    ///                 ^ expected a value, found the end of the filter
+    /// ```
+    pub fn display_with_source(&self, source: &str) -> String {
+        let column = source
+            .get(..self.position)
+            .map_or(self.position, |before| before.chars().count());
+        format!("{}\n{}^ {}", source, " ".repeat(column), self.message)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The levels, from least to most severe.
+const LEVELS: [&str; 5] = ["TRACE", "DEBUG", "INFO", "WARN", "ERROR"];
+
+/// How deep parentheses and `!` can be nested before parsing gives up, so that deeply nested
+/// filters can't blow the stack.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug)]
+enum Expr {
+    /// A chain of `||`s. Chains are kept flat, so that long ones don't nest.
+    Or(Vec<Expr>),
+    And(Vec<Expr>),
+    Not(Box<Expr>),
+    Compare(Box<Expr>, CompareOp, Box<Expr>),
+    Matches(Box<Expr>, Regex, bool),
+    Path(Vec<PathSegment>),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Expr {
+    fn evaluate<'a>(&'a self, data: &'a impl Resolve) -> Cow<'a, Value> {
+        let bool = |b: bool| Cow::Owned(Value::Bool(b));
+        match self {
+            Expr::Or(exprs) => bool(exprs.iter().any(|expr| expr.evaluate(data).is_truthy())),
+            Expr::And(exprs) => bool(exprs.iter().all(|expr| expr.evaluate(data).is_truthy())),
+            Expr::Not(expr) => bool(!expr.evaluate(data).is_truthy()),
+            Expr::Compare(lhs, op, rhs) => {
+                bool(compare(&lhs.evaluate(data), *op, &rhs.evaluate(data)))
+            }
+            Expr::Matches(expr, regex, negated) => {
+                let value = expr.evaluate(data);
+                let matched = match &*value {
+                    Value::Null => false,
+                    Value::String(s) => regex.is_match(s),
+                    value => regex.is_match(&value.to_string()),
+                };
+                bool(matched != *negated)
+            }
+            Expr::Path(path) => data.resolve(path).unwrap_or(Cow::Owned(Value::Null)),
+            Expr::Literal(value) => Cow::Borrowed(value),
+        }
+    }
+}
+
+fn compare(lhs: &Value, op: CompareOp, rhs: &Value) -> bool {
+    let ordering = match (lhs, rhs) {
+        (Value::Number(l), Value::Number(r)) => compare_numbers(l, r),
+        (Value::String(l), Value::String(r)) => match (level_severity(l), level_severity(r)) {
+            (Some(l), Some(r)) => Some(l.cmp(&r)),
+            _ => Some(l.cmp(r)),
+        },
+        (l, r) if l == r => Some(Ordering::Equal),
+        _ => None,
+    };
+    match op {
+        CompareOp::Eq => ordering == Some(Ordering::Equal),
+        CompareOp::Ne => ordering != Some(Ordering::Equal),
+        CompareOp::Lt => ordering == Some(Ordering::Less),
+        CompareOp::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        CompareOp::Gt => ordering == Some(Ordering::Greater),
+        CompareOp::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    }
+}
+
+/// Compare two numbers, exactly if they're both integers. As `f64`s, integers above 2^53 that
+/// differ can compare equal.
+fn compare_numbers(lhs: &serde_json::Number, rhs: &serde_json::Number) -> Option<Ordering> {
+    let as_integer = |n: &serde_json::Number| {
+        n.as_i64()
+            .map(i128::from)
+            .or_else(|| n.as_u64().map(i128::from))
+    };
+    match (as_integer(lhs), as_integer(rhs)) {
+        (Some(l), Some(r)) => Some(l.cmp(&r)),
+        _ => lhs.as_f64().partial_cmp(&rhs.as_f64()),
+    }
+}
+
+fn level_severity(s: &str) -> Option<usize> {
+    LEVELS.iter().position(|level| *level == s)
+}
+
+trait Truthy {
+    fn is_truthy(&self) -> bool;
+}
+
+impl Truthy for Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Number(n) => n.as_f64() != Some(0.0),
+            Value::String(s) => !s.is_empty(),
+            Value::Array(_) | Value::Object(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    String(String),
+    Number(serde_json::Number),
+    Dot,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Compare(CompareOp),
+    Match(bool),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(ident) => write!(f, "`{}`", ident),
+            Token::String(s) => write!(f, "{:?}", s),
+            Token::Number(n) => write!(f, "`{}`", n),
+            Token::Dot => f.write_str("`.`"),
+            Token::LBracket => f.write_str("`[`"),
+            Token::RBracket => f.write_str("`]`"),
+            Token::LParen => f.write_str("`(`"),
+            Token::RParen => f.write_str("`)`"),
+            Token::And => f.write_str("`&&`"),
+            Token::Or => f.write_str("`||`"),
+            Token::Not => f.write_str("`!`"),
+            Token::Compare(op) => f.write_str(match op {
+                CompareOp::Eq => "`==`",
+                CompareOp::Ne => "`!=`",
+                CompareOp::Lt => "`<`",
+                CompareOp::Le => "`<=`",
+                CompareOp::Gt => "`>`",
+                CompareOp::Ge => "`>=`",
+            }),
+            Token::Match(false) => f.write_str("`~`"),
+            Token::Match(true) => f.write_str("`!~`"),
+            Token::End => f.write_str("the end of the filter"),
+        }
+    }
+}
+
+fn lex(source: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        let next_is = |expected: char| source[position + c.len_utf8()..].starts_with(expected);
+        let (token, len) = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '.' => (Token::Dot, 1),
+            '[' => (Token::LBracket, 1),
+            ']' => (Token::RBracket, 1),
+            '(' => (Token::LParen, 1),
+            ')' => (Token::RParen, 1),
+            '~' => (Token::Match(false), 1),
+            '&' if next_is('&') => (Token::And, 2),
+            '|' if next_is('|') => (Token::Or, 2),
+            '=' if next_is('=') => (Token::Compare(CompareOp::Eq), 2),
+            '!' if next_is('=') => (Token::Compare(CompareOp::Ne), 2),
+            '!' if next_is('~') => (Token::Match(true), 2),
+            '!' => (Token::Not, 1),
+            '<' if next_is('=') => (Token::Compare(CompareOp::Le), 2),
+            '<' => (Token::Compare(CompareOp::Lt), 1),
+            '>' if next_is('=') => (Token::Compare(CompareOp::Ge), 2),
+            '>' => (Token::Compare(CompareOp::Gt), 1),
+            '"' => lex_string(&source[position..], position)?,
+            c if c == '-' || c.is_ascii_digit() => lex_number(&source[position..], position)?,
+            c if c.is_alphabetic() || c == '_' => {
+                let len = source[position..]
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(source.len() - position);
+                let ident = &source[position..position + len];
+                (Token::Ident(ident.to_owned()), len)
+            }
+            '&' | '|' | '=' => {
+                return Err(ParseError::new(
+                    format!("unexpected `{}`, did you mean `{}{}`?", c, c, c),
+                    position,
+                ))
+            }
+            c => {
+                return Err(ParseError::new(
+                    format!("unexpected character `{}`", c),
+                    position,
+                ))
+            }
+        };
+        tokens.push((token, position));
+        while chars.peek().is_some_and(|&(p, _)| p < position + len) {
+            chars.next();
+        }
+    }
+
+    tokens.push((Token::End, source.len()));
+    Ok(tokens)
+}
+
+/// Lex a string, starting at the opening quote.
+fn lex_string(source: &str, position: usize) -> Result<(Token, usize), ParseError> {
+    let mut s = String::new();
+    let mut chars = source.char_indices().skip(1);
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '"' => return Ok((Token::String(s), offset + 1)),
+            '\\' => match chars.next() {
+                Some((_, '"')) => s.push('"'),
+                Some((_, '\\')) => s.push('\\'),
+                Some((_, 'n')) => s.push('\n'),
+                Some((_, 't')) => s.push('\t'),
+                Some((offset, c)) => {
+                    return Err(ParseError::new(
+                        format!("unknown escape `\\{}` in string", c),
+                        position + offset - 1,
+                    ))
+                }
+                None => break,
+            },
+            c => s.push(c),
+        }
+    }
+    Err(ParseError::new("unterminated string", position))
+}
+
+/// Lex a number, starting at its first character.
+fn lex_number(source: &str, position: usize) -> Result<(Token, usize), ParseError> {
+    let len = source
+        .char_indices()
+        .skip(1)
+        .find(|&(_, c)| !(c.is_ascii_alphanumeric() || c == '.' || c == '+' || c == '-'))
+        .map_or(source.len(), |(offset, _)| offset);
+    let text = &source[..len];
+    match serde_json::from_str::<serde_json::Number>(text) {
+        Ok(number) => Ok((Token::Number(number), len)),
+        Err(_) => Err(ParseError::new(
+            format!("`{}` is not a valid number", text),
+            position,
+        )),
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    /// How many parentheses and `!`s the parser is inside.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &(Token, usize) {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> (Token, usize) {
+        let token = self.tokens[self.position].clone();
+        if token.0 != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    /// Parse something nested inside a `(` or `!` at `position`, as long as it isn't nested too
+    /// deeply.
+    fn nested<T>(
+        &mut self,
+        position: usize,
+        parse: impl FnOnce(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<T, ParseError> {
+        if self.depth == MAX_DEPTH {
+            return Err(ParseError::new("the filter is nested too deeply", position));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut exprs = vec![self.parse_and()?];
+        while self.peek().0 == Token::Or {
+            self.next();
+            exprs.push(self.parse_and()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::Or(exprs),
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut exprs = vec![self.parse_not()?];
+        while self.peek().0 == Token::And {
+            self.next();
+            exprs.push(self.parse_not()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::And(exprs),
+        })
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, ParseError> {
+        if let (Token::Not, position) = *self.peek() {
+            self.next();
+            let expr = self.nested(position, Self::parse_not)?;
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, ParseError> {
+        let lhs = self.parse_operand()?;
+        match self.peek().0 {
+            Token::Compare(op) => {
+                self.next();
+                let rhs = self.parse_operand()?;
+                Ok(Expr::Compare(Box::new(lhs), op, Box::new(rhs)))
+            }
+            Token::Match(negated) => {
+                self.next();
+                match self.next() {
+                    (Token::String(pattern), position) => match Regex::new(&pattern) {
+                        Ok(regex) => Ok(Expr::Matches(Box::new(lhs), regex, negated)),
+                        Err(err) => Err(ParseError::new(
+                            format!("invalid regular expression: {}", err),
+                            position,
+                        )),
+                    },
+                    (token, position) => Err(ParseError::new(
+                        format!("expected a regular expression string, found {}", token),
+                        position,
+                    )),
+                }
+            }
+            _ => Ok(lhs),
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Expr, ParseError> {
+        match self.next() {
+            (Token::LParen, position) => {
+                let expr = self.nested(position, Self::parse_or)?;
+                match self.next() {
+                    (Token::RParen, _) => Ok(expr),
+                    (Token::End, _) => Err(ParseError::new(
+                        "unclosed `(`, expected a matching `)`",
+                        position,
+                    )),
+                    (token, position) => Err(ParseError::new(
+                        format!("expected `)`, found {}", token),
+                        position,
+                    )),
+                }
+            }
+            (Token::String(s), _) => Ok(Expr::Literal(Value::String(s))),
+            (Token::Number(n), _) => Ok(Expr::Literal(Value::Number(n))),
+            (Token::Ident(ident), _) => match ident.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                level if LEVELS.contains(&level) => Ok(Expr::Literal(Value::String(ident))),
+                _ => self.parse_path(ident),
+            },
+            (token, position) => Err(ParseError::new(
+                format!("expected a value, found {}", token),
+                position,
+            )),
+        }
+    }
+
+    fn parse_path(&mut self, first: String) -> Result<Expr, ParseError> {
+        let mut path = vec![PathSegment::Key(first)];
+        loop {
+            match self.peek().0 {
+                Token::Dot => {
+                    self.next();
+                    match self.next() {
+                        (Token::Ident(key), _) | (Token::String(key), _) => {
+                            path.push(PathSegment::Key(key))
+                        }
+                        (token, position) => {
+                            return Err(ParseError::new(
+                                format!("expected a key after `.`, found {}", token),
+                                position,
+                            ))
+                        }
+                    }
+                }
+                Token::LBracket => {
+                    self.next();
+                    let index = match self.next() {
+                        (Token::Number(n), position) => n.as_u64().ok_or_else(|| {
+                            ParseError::new(format!("`{}` is not a valid array index", n), position)
+                        })?,
+                        (token, position) => {
+                            return Err(ParseError::new(
+                                format!("expected an array index, found {}", token),
+                                position,
+                            ))
+                        }
+                    };
+                    match self.next() {
+                        (Token::RBracket, _) => path.push(PathSegment::Index(index as usize)),
+                        (token, position) => {
+                            return Err(ParseError::new(
+                                format!("expected `]`, found {}", token),
+                                position,
+                            ))
+                        }
+                    }
+                }
+                _ => return Ok(Expr::Path(path)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        lex(source)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    fn matches(filter: &str, data: Value) -> bool {
+        Filter::parse(filter).unwrap().matches(&data)
+    }
+
+    fn error(source: &str) -> String {
+        Filter::parse(source)
+            .unwrap_err()
+            .display_with_source(source)
+    }
+
+    #[test]
+    fn lexes_operators_and_literals() {
+        assert_eq!(
+            tokens(r#"a.b[0] >= -1.5 && !c != "x\"y" || d !~ "e""#),
+            vec![
+                Token::Ident("a".into()),
+                Token::Dot,
+                Token::Ident("b".into()),
+                Token::LBracket,
+                Token::Number(0.into()),
+                Token::RBracket,
+                Token::Compare(CompareOp::Ge),
+                Token::Number(serde_json::Number::from_f64(-1.5).unwrap()),
+                Token::And,
+                Token::Not,
+                Token::Ident("c".into()),
+                Token::Compare(CompareOp::Ne),
+                Token::String("x\"y".into()),
+                Token::Or,
+                Token::Ident("d".into()),
+                Token::Match(true),
+                Token::String("e".into()),
+                Token::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn lexes_token_positions() {
+        let positions: Vec<usize> = lex("a  == \"é\" ").unwrap().iter().map(|t| t.1).collect();
+        assert_eq!(positions, vec![0, 3, 6, 11]);
+    }
+
+    #[test]
+    fn parses_precedence() {
+        // `&&` binds tighter than `||`, and `!` tighter than both.
+        assert!(matches("a || b && c", json!({ "a": true })));
+        assert!(!matches("(a || b) && c", json!({ "a": true })));
+        assert!(matches("!a && b", json!({ "b": true })));
+        assert!(!matches("!(a || b)", json!({ "b": true })));
+    }
+
+    #[test]
+    fn parses_paths() {
+        let data = json!({
+            "fields": { "http.method": "GET", "tags": ["a", "b"] },
+            "spans": [{ "name": "request" }],
+        });
+        assert!(matches(r#"fields."http.method" == "GET""#, data.clone()));
+        assert!(matches(r#"fields.tags[1] == "b""#, data.clone()));
+        assert!(matches(r#"spans[0].name == "request""#, data.clone()));
+        assert!(!matches("spans[1].name", data));
+    }
+
+    #[test]
+    fn resolves_span_fields_in_either_layout() {
+        let flat = json!({
+            "span": { "name": "load", "id": 7, "fields.name": "x" },
+            "spans": [{ "name": "request", "path": "/" }, { "name": "load", "id": 7 }],
+        });
+        let nested = json!({
+            "spans": [
+                { "name": "request", "fields": { "path": "/" } },
+                { "name": "load", "fields": { "id": 7, "name": "x" } },
+            ],
+        });
+        for data in [flat, nested] {
+            assert!(matches(r#"span.name == "load""#, data.clone()));
+            assert!(matches("span.id == 7", data.clone()));
+            assert!(matches("span.fields.id == 7", data.clone()));
+            assert!(matches(r#"span.path == "/""#, data.clone()));
+            assert!(matches(r#"span.fields.name == "x""#, data.clone()));
+            assert!(!matches("span.missing", data));
+        }
+        assert!(matches(
+            r#"span.path == "/""#,
+            json!({ "root_span": { "path": "/" } })
+        ));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let parens = format!("{}a{}", "(".repeat(10_000), ")".repeat(10_000));
+        assert_eq!(
+            Filter::parse(&parens).unwrap_err().message,
+            "the filter is nested too deeply"
+        );
+        let nots = format!("{}a", "!".repeat(100_000));
+        assert_eq!(
+            Filter::parse(&nots).unwrap_err().message,
+            "the filter is nested too deeply"
+        );
+        assert!(matches("((!!a))", json!({ "a": true })));
+
+        // Long chains don't nest, so they're fine.
+        let chain = vec!["a"; 100_000].join(" || ");
+        assert!(matches(&chain, json!({ "a": true })));
+    }
+
+    #[test]
+    fn evaluates_levels_by_severity() {
+        assert!(matches("level >= WARN", json!({ "level": "ERROR" })));
+        assert!(!matches("level >= WARN", json!({ "level": "INFO" })));
+        assert!(matches("level < INFO", json!({ "level": "TRACE" })));
+    }
+
+    #[test]
+    fn evaluates_regexes_and_truthiness() {
+        let data = json!({ "path": "/admin/users", "count": 0, "name": "", "user": null });
+        assert!(matches(r#"path ~ "^/admin""#, data.clone()));
+        assert!(matches(r#"path !~ "^/api""#, data.clone()));
+        assert!(!matches(r#"user ~ ".*""#, data.clone()));
+        assert!(!matches("count || name || user || missing", data));
+    }
+
+    #[test]
+    fn compares_large_integers_exactly() {
+        let data = json!({ "id": u64::MAX, "small": i64::MIN, "big": 9_007_199_254_740_993u64 });
+        assert!(matches("id == 18446744073709551615", data.clone()));
+        assert!(!matches("id == 18446744073709551614", data.clone()));
+        assert!(matches("id > 18446744073709551614", data.clone()));
+        assert!(matches("big != 9007199254740992", data.clone()));
+        assert!(matches("big > 9007199254740992", data.clone()));
+        assert!(matches("small < -9223372036854775807", data.clone()));
+        assert!(matches("small < id", data));
+    }
+
+    #[test]
+    fn compares_integers_with_floats() {
+        assert!(matches("n < 1.5", json!({ "n": 1 })));
+        assert!(matches("n == 2.0", json!({ "n": 2 })));
+        assert!(!matches("n == 2", json!({ "n": "2" })));
+    }
+
+    #[test]
+    fn points_at_unexpected_characters() {
+        assert_eq!(
+            error("level = WARN"),
+            "level = WARN\n      ^ unexpected `=`, did you mean `==`?"
+        );
+        assert_eq!(error("a # b"), "a # b\n  ^ unexpected character `#`");
+    }
+
+    #[test]
+    fn points_at_bad_literals() {
+        assert_eq!(
+            error(r#"a == "abc"#),
+            "a == \"abc\n     ^ unterminated string"
+        );
+        assert_eq!(
+            error(r#"a == "\q""#),
+            "a == \"\\q\"\n      ^ unknown escape `\\q` in string"
+        );
+        assert_eq!(
+            error("a == 1.2.3"),
+            "a == 1.2.3\n     ^ `1.2.3` is not a valid number"
+        );
+    }
+
+    #[test]
+    fn points_at_parse_errors() {
+        assert_eq!(
+            error("level >= WARN &&"),
+            "level >= WARN &&\n                ^ expected a value, found the end of the filter"
+        );
+        assert_eq!(
+            error("(a || b"),
+            "(a || b\n^ unclosed `(`, expected a matching `)`"
+        );
+        assert_eq!(
+            error("a b"),
+            "a b\n  ^ expected `&&`, `||`, or the end of the filter, found `b`"
+        );
+        assert_eq!(
+            error("spans[-1]"),
+            "spans[-1]\n      ^ `-1` is not a valid array index"
+        );
+        assert_eq!(
+            error(r#"a ~ "(""#),
+            "a ~ \"(\"\n    ^ invalid regular expression: regex parse error:\n    (\n    ^\nerror: unclosed group"
+        );
+    }
+
+    #[test]
+    fn counts_columns_in_characters() {
+        assert_eq!(
+            error(r#""é" == #"#),
+            "\"é\" == #\n       ^ unexpected character `#`"
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::serde_json_adapter::SerdeJsonAdapter;
+use crate::serde_adapter::SerdeAdapter;
 
 /// Logs a serializable object in a tracing context.
 ///
@@ -84,12 +84,11 @@ where
 }
 
 /// A less hacky way to include rich JSON data in our tracing data, using Valuable
-pub fn tracing_json_new_helper<S>(value: &S) -> SerdeJsonAdapter<serde_json::Value>
+pub fn tracing_json_new_helper<S>(value: &S) -> SerdeAdapter<&S>
 where
     S: serde::Serialize,
 {
-    let json = serde_json::to_value(value).unwrap();
-    SerdeJsonAdapter::new(json)
+    SerdeAdapter::new(value)
 }
 
 pub(crate) use tracing_json_new_macro as tracing_json_new;
@@ -1,19 +1,37 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::info;
+use tracing::{debug, error, info, info_span, warn, Level};
 use tracing_subscriber::prelude::*;
 use valuable::Valuable;
 
+mod bench;
 mod custom_layer;
+mod debug_json;
+mod filter_expr;
 mod macros;
+mod serde_adapter;
 mod serde_json_adapter;
 
+use custom_layer::{
+    BytesEncoding, CanonicalLines, CardinalityFallback, EventData, FlightRecorder, HeadSampling,
+    KeyCase, KeyDepth, NonFiniteFloats, PanicHook, Processed, Promotion, RecorderScope,
+    RepeatedFieldPolicy, SpanContext, SpanLayout, SpanOutput, TailSampling, TypeMismatch,
+    TypeScope, UnsetFields, MAX_SAFE_INTEGER,
+};
 use macros::{tracing_json_new, tracing_json_old};
+use serde_adapter::SerdeAdapter;
 use serde_json_adapter::SerdeJsonAdapter;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("bench") => return bench::run(),
+        Some("filter") => return filter_lines(args.get(2).map_or("", String::as_str)),
+        _ => {}
+    }
+
     {
         let _default = tracing_subscriber::registry()
             .with(tracing_subscriber::fmt::layer().compact())
@@ -34,6 +52,197 @@ fn main() {
             .set_default();
         log_some_things();
     }
+
+    {
+        let _default = tracing_subscriber::registry()
+            .with(
+                custom_layer::CustomJsonLayer::default()
+                    .with_span_layout(SpanLayout::Nested)
+                    .with_repeated_fields(RepeatedFieldPolicy::History)
+                    .with_span_context(SpanContext::Object)
+                    .with_uninherited_fields(["age"])
+                    .with_unset_fields(UnsetFields::List)
+                    .with_message_templates(true)
+                    .with_span_output(SpanOutput::Leaf)
+                    .with_span_output_for(Level::ERROR, SpanOutput::All)
+                    .with_static_field("service", env!("CARGO_PKG_NAME"))
+                    .with_static_field("version", env!("CARGO_PKG_VERSION"))
+                    .with_env_field("host", "HOSTNAME")
+                    .with_dynamic_valuable_field("thread", || {
+                        std::thread::current().name().map(String::from)
+                    })
+                    .with_processor(|event: &mut EventData| {
+                        // `json_old` and `json_new` end up the same, so only keep one of them.
+                        event.fields.shift_remove("json_old");
+                        Processed::Keep
+                    })
+                    .with_promotion(Promotion::copy("fields.json_new/fancyId", "fancy_id"))
+                    .with_promotion(Promotion::moved("fields.json/aliases/0", "first_alias"))
+                    .with_large_integer_limit(MAX_SAFE_INTEGER)
+                    .with_non_finite_floats(NonFiniteFloats::String)
+                    .with_bytes_encoding(BytesEncoding::Hex)
+                    .with_debug_parsing(true)
+                    .with_dotted_field_expansion(true)
+                    .with_type_stability(TypeScope::Callsite, TypeMismatch::Suffix)
+                    .with_cardinality_limit(3, CardinalityFallback::Pairs)
+                    .with_key_case(KeyCase::Camel, KeyDepth::Fields)
+                    .with_key_rename("timestamp", "@timestamp")
+                    .with_flight_recorder(
+                        FlightRecorder::new(Level::INFO)
+                            .with_scope(RecorderScope::RootSpan)
+                            .with_capacity(200)
+                            .with_window(Duration::from_secs(30)),
+                    )
+                    .with_filter(
+                        r#"message != "adapter test" || fields.json.name ~ "^O""#
+                            .parse()
+                            .unwrap(),
+                    ),
+            )
+            .set_default();
+        log_some_things();
+
+        // Panics are logged through the layer too, in the span they happened in.
+        PanicHook::new()
+            .with_forced_backtrace(true)
+            .with_flush(|| {
+                let _ = std::io::Write::flush(&mut std::io::stdout());
+            })
+            .install();
+        let _ = std::panic::catch_unwind(|| {
+            let _span = info_span!("risky_spell", spell = "fireball").entered();
+            // Too verbose to write, unless something goes wrong.
+            debug!(mana = 3, "charging");
+            panic!("the spell backfired");
+        });
+        let _ = std::panic::take_hook();
+    }
+
+    {
+        // Only the spans that went wrong (or took too long) are written, each ending with its
+        // canonical line.
+        let _default = tracing_subscriber::registry()
+            .with(
+                custom_layer::CustomJsonLayer::default()
+                    .with_tail_sampling(
+                        TailSampling::new(0.0)
+                            .with_latency_threshold(Duration::from_secs(1))
+                            .with_span_cap(64 << 10)
+                            .with_global_cap(16 << 20),
+                    )
+                    .with_canonical_lines(
+                        CanonicalLines::new()
+                            .for_name("request")
+                            .for_target("tracing_valuable_test::jobs")
+                            .with_counter("db_queries"),
+                    ),
+            )
+            .set_default();
+        for (request, status) in [(1, 200), (2, 500)] {
+            let _span = info_span!("request", request).entered();
+            info!(status, db_queries = 2, "handled");
+            if status >= 500 {
+                error!("something went wrong");
+            }
+        }
+    }
+
+    {
+        // Whole traces are written or not, and the same correlation id always gets the same
+        // decision.
+        let _default = tracing_subscriber::registry()
+            .with(
+                custom_layer::CustomJsonLayer::default().with_head_sampling(
+                    HeadSampling::new(0.5)
+                        .with_rate("job", 0.0)
+                        .with_trace_id_field("correlation_id")
+                        .with_force_field("debug"),
+                ),
+            )
+            .set_default();
+        for correlation_id in ["4bf92f35", "00f067aa", "a3ce929d", "0e0e4736"] {
+            let _span = info_span!("request", correlation_id).entered();
+            let _query = info_span!("query").entered();
+            info!("ran a query");
+        }
+        let _job = info_span!("job", debug = true).entered();
+        info!("always written");
+    }
+
+    {
+        // A burst of the same warning is written once, followed by a summary of the repeats.
+        let _default = tracing_subscriber::registry()
+            .with(
+                custom_layer::CustomJsonLayer::default()
+                    .with_repeat_coalescing(Duration::from_secs(10)),
+            )
+            .set_default();
+        for attempt in 0..1000 {
+            let connected = attempt == 999;
+            warn!(connected, "connecting to the database");
+        }
+    }
+
+    // The same events again, with the options the examples above don't show.
+    for layer in [
+        custom_layer::CustomJsonLayer::default()
+            .with_repeated_fields(RepeatedFieldPolicy::FirstWins)
+            .with_span_context(SpanContext::Fields)
+            .with_key_case(KeyCase::Kebab, KeyDepth::TopLevel)
+            .with_type_stability(TypeScope::Global, TypeMismatch::Report)
+            .with_bytes_encoding(BytesEncoding::Utf8Lossy)
+            .with_file_field("pod", "/etc/podinfo/name")
+            .with_dynamic_field("pid", || Some(std::process::id().into()))
+            .with_processor(|event: &mut EventData| {
+                // Write each copy of the fancy value on a line of its own.
+                if event.message != Some(json!("fancy")) {
+                    return Processed::Keep;
+                }
+                let split = event
+                    .fields
+                    .keys()
+                    .filter(|key| key.starts_with("json_"))
+                    .map(|key| {
+                        let mut split = event.clone();
+                        split.fields.retain(|other, _| other == key);
+                        split
+                    })
+                    .collect();
+                Processed::Split(split)
+            }),
+        custom_layer::CustomJsonLayer::default()
+            .with_repeated_fields(RepeatedFieldPolicy::Collect)
+            .with_top_level_message(false)
+            .with_span_output(SpanOutput::None)
+            .with_key_case(KeyCase::Snake, KeyDepth::Recursive)
+            .with_cardinality_limit(2, CardinalityFallback::String)
+            .with_non_finite_floats(NonFiniteFloats::NullWithWarning),
+    ] {
+        let _default = tracing_subscriber::registry().with(layer).set_default();
+        log_some_things();
+    }
+}
+
+/// Read JSON lines from stdin and print the ones that match a filter, e.g.
+///
+/// ```text
+/// cargo run | cargo run -- filter 'level >= WARN || fields.json.name == "One"'
+/// ```
+fn filter_lines(filter: &str) {
+    let filter: filter_expr::Filter = match filter.parse() {
+        Ok(filter) => filter,
+        Err(err) => {
+            eprintln!("{}", err.display_with_source(filter));
+            std::process::exit(2);
+        }
+    };
+    for line in std::io::stdin().lines().map_while(Result::ok) {
+        if let Ok(json) = serde_json::from_str::<serde_json::Value>(&line) {
+            if filter.matches(&json) {
+                println!("{}", line);
+            }
+        }
+    }
 }
 
 fn log_some_things() {
@@ -49,8 +258,7 @@ fn log_some_things() {
 
     info!(
         message = "as_serialize",
-        serialize_and_valuable =
-            SerdeJsonAdapter::new(serde_json::to_value(serialize_and_valuable).unwrap()).as_value()
+        serialize_and_valuable = SerdeAdapter::new(&serialize_and_valuable).as_value()
     );
 
     for json in [
@@ -71,6 +279,23 @@ fn log_some_things() {
         );
     }
 
+    info!(
+        message = "request",
+        http.method = "GET",
+        http.status_code = 200,
+        http.request.id = 7
+    );
+
+    info!(
+        message = "numbers",
+        big = u64::MAX,
+        small = 42_u64,
+        ratio = f64::NAN,
+        huge = i128::MIN,
+        bytes = &b"tracing\xff"[..],
+        json = SerdeJsonAdapter::new(json!({ "id": 9_007_199_254_740_993_u64 })).as_value()
+    );
+
     let fancy = FancySerialization {
         fancy_id: String::from("WOO!"),
         fizz_buzz: 15.into(),
@@ -80,11 +305,28 @@ fn log_some_things() {
             (String::from("three"), String::from("3")),
         ]),
     };
+    let span = info_span!(
+        "load_wizard",
+        name = "Gandalf",
+        age = 2000,
+        state = "loading",
+        spell = tracing::field::Empty,
+        staff = tracing::field::Empty
+    );
+    let _enter = span.enter();
+    span.record("state", "loaded");
+    span.record("staff", "wooden");
+    info!(
+        message = "loaded {wizard}, age {age}",
+        wizard = "Gandalf",
+        age = 2000
+    );
     info!(
         message = "fancy",
         json_old = tracing_json_old!(fancy),
         json_new = tracing_json_new!(fancy),
     );
+    info!(message = "debug", fancy = ?fancy);
 }
 
 #[derive(Debug, Serialize, Deserialize, Valuable)]
@@ -117,10 +359,10 @@ impl From<usize> for FizzBuzz {
 
 impl FizzBuzz {
     pub fn is_fizz(&self) -> bool {
-        self.0 % 3 == 0
+        self.0.is_multiple_of(3)
     }
     pub fn is_buzz(&self) -> bool {
-        self.0 % 5 == 0
+        self.0.is_multiple_of(5)
     }
     pub fn is_fizz_buzz(&self) -> bool {
         self.is_fizz() && self.is_buzz()
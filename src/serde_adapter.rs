@@ -0,0 +1,806 @@
+use serde::ser::{self, Serialize};
+use std::cell::OnceCell;
+use std::fmt;
+use valuable::{
+    EnumDef, Enumerable, Fields, Mappable, NamedField, NamedValues, StructDef, Structable,
+    Tuplable, TupleDef, Valuable, Value, Variant, VariantDef, Visit,
+};
+
+/// An adapter that exposes anything that implements `serde::Serialize` as a `valuable::Valuable`,
+/// without going through `serde_json::Value`.
+///
+/// The first time the value is looked at, it's serialized with a `serde::Serializer` that builds a
+/// tree of owned values, which is then what's handed to visitors. Serde's shapes are kept, so a
+/// struct is a `Structable`, an enum an `Enumerable`, and a tuple a `Tuplable`, rather than
+/// everything becoming a map or a list:
+///
+/// * Structs are `Structable`s with named fields, and tuple structs (including newtype structs)
+///   are `Structable`s with unnamed fields. Unit structs are `Value::Unit`.
+/// * Enum variants are `Enumerable`s, with named fields for struct variants, and unnamed fields
+///   for the others (none, for unit variants).
+/// * Tuples are `Tuplable`s, sequences are `Listable`s, and maps are `Mappable`s.
+/// * `None` and `()` are `Value::Unit`, and `Some(x)` is just `x`.
+///
+/// If serializing fails, the value is a `Value::Error`.
+///
+/// ```
+/// # use tracing_valuable_testing::serde_adapter::SerdeAdapter;
+/// # use valuable::Valuable;
+/// #[derive(serde::Serialize)]
+/// struct Wizard {
+///   name: String,
+///   age: usize,
+/// };
+///
+/// let wizard = Wizard { name: String::from("Gandalf"), age: 2000 };
+/// tracing::info!(id = 100, wizard = SerdeAdapter::new(&wizard).as_value())
+/// ```
+pub struct SerdeAdapter<T> {
+    value: T,
+    tree: OnceCell<Result<Node, SerializeError>>,
+}
+
+impl<T> SerdeAdapter<T>
+where
+    T: Serialize,
+{
+    /// Wrap something serializable (or a reference to it) in the adapter so it can be treated as a
+    /// `valuable::Valuable`.
+    pub fn new(value: T) -> Self {
+        SerdeAdapter {
+            value,
+            tree: OnceCell::new(),
+        }
+    }
+
+    fn tree(&self) -> &Result<Node, SerializeError> {
+        self.tree
+            .get_or_init(|| self.value.serialize(TreeSerializer))
+    }
+}
+
+impl<T> Valuable for SerdeAdapter<T>
+where
+    T: Serialize,
+{
+    fn as_value(&self) -> Value<'_> {
+        match self.tree() {
+            Ok(node) => node.as_value(),
+            Err(err) => Value::Error(err),
+        }
+    }
+
+    fn visit(&self, visit: &mut dyn Visit) {
+        match self.tree() {
+            Ok(node) => node.visit(visit),
+            Err(err) => visit.visit_value(Value::Error(err)),
+        }
+    }
+}
+
+/// An error from serializing a value for `SerdeAdapter`.
+#[derive(Debug)]
+pub struct SerializeError(String);
+
+impl fmt::Display for SerializeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+impl ser::Error for SerializeError {
+    fn custom<M: fmt::Display>(message: M) -> Self {
+        SerializeError(message.to_string())
+    }
+}
+
+/// A serialized value.
+enum Node {
+    Unit,
+    Bool(bool),
+    I64(i64),
+    I128(i128),
+    U64(u64),
+    U128(u128),
+    F32(f32),
+    F64(f64),
+    Char(char),
+    String(String),
+    Bytes(Vec<u8>),
+    Seq(Vec<Node>),
+    Tuple(TupleNode),
+    Map(MapNode),
+    Struct(StructNode),
+    Enum(EnumNode),
+}
+
+struct TupleNode(Vec<Node>);
+
+struct MapNode(Vec<(Node, Node)>);
+
+/// The fields of a struct or an enum variant. Tuple structs and variants have no names.
+struct FieldsNode {
+    names: Option<Vec<NamedField<'static>>>,
+    values: Vec<Node>,
+}
+
+struct StructNode {
+    name: &'static str,
+    fields: FieldsNode,
+}
+
+struct EnumNode {
+    name: &'static str,
+    variant: &'static str,
+    fields: FieldsNode,
+}
+
+impl Valuable for Node {
+    fn as_value(&self) -> Value<'_> {
+        match self {
+            Node::Unit => Value::Unit,
+            Node::Bool(b) => Value::Bool(*b),
+            Node::I64(n) => Value::I64(*n),
+            Node::I128(n) => Value::I128(*n),
+            Node::U64(n) => Value::U64(*n),
+            Node::U128(n) => Value::U128(*n),
+            Node::F32(n) => Value::F32(*n),
+            Node::F64(n) => Value::F64(*n),
+            Node::Char(c) => Value::Char(*c),
+            Node::String(s) => Value::String(s),
+            Node::Bytes(bytes) => Value::Listable(bytes),
+            Node::Seq(seq) => Value::Listable(seq),
+            Node::Tuple(tuple) => Value::Tuplable(tuple),
+            Node::Map(map) => Value::Mappable(map),
+            Node::Struct(structable) => Value::Structable(structable),
+            Node::Enum(enumerable) => Value::Enumerable(enumerable),
+        }
+    }
+
+    fn visit(&self, visit: &mut dyn Visit) {
+        match self {
+            Node::Bytes(bytes) => bytes.visit(visit),
+            Node::Seq(seq) => seq.visit(visit),
+            Node::Tuple(tuple) => tuple.visit(visit),
+            Node::Map(map) => map.visit(visit),
+            Node::Struct(structable) => structable.visit(visit),
+            Node::Enum(enumerable) => enumerable.visit(visit),
+            _ => visit.visit_value(self.as_value()),
+        }
+    }
+}
+
+impl Valuable for TupleNode {
+    fn as_value(&self) -> Value<'_> {
+        Value::Tuplable(self)
+    }
+
+    fn visit(&self, visit: &mut dyn Visit) {
+        let values: Vec<Value<'_>> = self.0.iter().map(Node::as_value).collect();
+        visit.visit_unnamed_fields(&values);
+    }
+}
+
+impl Tuplable for TupleNode {
+    fn definition(&self) -> TupleDef {
+        TupleDef::new_static(self.0.len())
+    }
+}
+
+impl Valuable for MapNode {
+    fn as_value(&self) -> Value<'_> {
+        Value::Mappable(self)
+    }
+
+    fn visit(&self, visit: &mut dyn Visit) {
+        for (key, value) in &self.0 {
+            visit.visit_entry(key.as_value(), value.as_value());
+        }
+    }
+}
+
+impl Mappable for MapNode {
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl FieldsNode {
+    fn definition(&self) -> Fields<'_> {
+        match &self.names {
+            Some(names) => Fields::Named(names),
+            None => Fields::Unnamed(self.values.len()),
+        }
+    }
+
+    fn visit(&self, visit: &mut dyn Visit) {
+        let values: Vec<Value<'_>> = self.values.iter().map(Node::as_value).collect();
+        match &self.names {
+            Some(names) => visit.visit_named_fields(&NamedValues::new(names, &values)),
+            None => visit.visit_unnamed_fields(&values),
+        }
+    }
+}
+
+impl Valuable for StructNode {
+    fn as_value(&self) -> Value<'_> {
+        Value::Structable(self)
+    }
+
+    fn visit(&self, visit: &mut dyn Visit) {
+        self.fields.visit(visit);
+    }
+}
+
+impl Structable for StructNode {
+    fn definition(&self) -> StructDef<'_> {
+        // The number of unnamed fields is all there is to know about them, so tuple structs can be
+        // static, which is also what makes newtype structs come out as just their field.
+        match self.fields.definition() {
+            Fields::Unnamed(len) => StructDef::new_static(self.name, Fields::Unnamed(len)),
+            fields => StructDef::new_dynamic(self.name, fields),
+        }
+    }
+}
+
+impl Valuable for EnumNode {
+    fn as_value(&self) -> Value<'_> {
+        Value::Enumerable(self)
+    }
+
+    fn visit(&self, visit: &mut dyn Visit) {
+        self.fields.visit(visit);
+    }
+}
+
+impl Enumerable for EnumNode {
+    fn definition(&self) -> EnumDef<'_> {
+        // Only the variant that was serialized is known.
+        EnumDef::new_dynamic(self.name, &[])
+    }
+
+    fn variant(&self) -> Variant<'_> {
+        Variant::Dynamic(VariantDef::new(self.variant, self.fields.definition()))
+    }
+}
+
+/// Serializes a value into a `Node`.
+struct TreeSerializer;
+
+impl ser::Serializer for TreeSerializer {
+    type Ok = Node;
+    type Error = SerializeError;
+
+    type SerializeSeq = SerializeSeq;
+    type SerializeTuple = SerializeSeq;
+    type SerializeTupleStruct = SerializeSeq;
+    type SerializeTupleVariant = SerializeSeq;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeStruct;
+    type SerializeStructVariant = SerializeStruct;
+
+    fn serialize_bool(self, v: bool) -> Result<Node, SerializeError> {
+        Ok(Node::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Node, SerializeError> {
+        Ok(Node::I64(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Node, SerializeError> {
+        Ok(Node::I64(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Node, SerializeError> {
+        Ok(Node::I64(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Node, SerializeError> {
+        Ok(Node::I64(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Node, SerializeError> {
+        Ok(Node::I128(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Node, SerializeError> {
+        Ok(Node::U64(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Node, SerializeError> {
+        Ok(Node::U64(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Node, SerializeError> {
+        Ok(Node::U64(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Node, SerializeError> {
+        Ok(Node::U64(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Node, SerializeError> {
+        Ok(Node::U128(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Node, SerializeError> {
+        Ok(Node::F32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Node, SerializeError> {
+        Ok(Node::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Node, SerializeError> {
+        Ok(Node::Char(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Node, SerializeError> {
+        Ok(Node::String(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Node, SerializeError> {
+        Ok(Node::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Node, SerializeError> {
+        Ok(Node::Unit)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Node, SerializeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Node, SerializeError> {
+        Ok(Node::Unit)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Node, SerializeError> {
+        Ok(Node::Unit)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Node, SerializeError> {
+        Ok(Node::Enum(EnumNode {
+            name,
+            variant,
+            fields: FieldsNode {
+                names: None,
+                values: Vec::new(),
+            },
+        }))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Node, SerializeError> {
+        Ok(Node::Struct(StructNode {
+            name,
+            fields: FieldsNode {
+                names: None,
+                values: vec![value.serialize(self)?],
+            },
+        }))
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Node, SerializeError> {
+        Ok(Node::Enum(EnumNode {
+            name,
+            variant,
+            fields: FieldsNode {
+                names: None,
+                values: vec![value.serialize(self)?],
+            },
+        }))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeSeq, SerializeError> {
+        Ok(SerializeSeq::new(SeqKind::Seq, len.unwrap_or(0)))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeSeq, SerializeError> {
+        Ok(SerializeSeq::new(SeqKind::Tuple, len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<SerializeSeq, SerializeError> {
+        Ok(SerializeSeq::new(SeqKind::Struct(name), len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeSeq, SerializeError> {
+        Ok(SerializeSeq::new(SeqKind::Variant(name, variant), len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap, SerializeError> {
+        Ok(SerializeMap {
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        name: &'static str,
+        len: usize,
+    ) -> Result<SerializeStruct, SerializeError> {
+        Ok(SerializeStruct::new(name, None, len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeStruct, SerializeError> {
+        Ok(SerializeStruct::new(name, Some(variant), len))
+    }
+}
+
+/// What a list of values turns into.
+enum SeqKind {
+    Seq,
+    Tuple,
+    Struct(&'static str),
+    Variant(&'static str, &'static str),
+}
+
+struct SerializeSeq {
+    kind: SeqKind,
+    values: Vec<Node>,
+}
+
+impl SerializeSeq {
+    fn new(kind: SeqKind, len: usize) -> Self {
+        SerializeSeq {
+            kind,
+            values: Vec::with_capacity(len),
+        }
+    }
+
+    fn push<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), SerializeError> {
+        self.values.push(value.serialize(TreeSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Node, SerializeError> {
+        let fields = |values| FieldsNode {
+            names: None,
+            values,
+        };
+        Ok(match self.kind {
+            SeqKind::Seq => Node::Seq(self.values),
+            SeqKind::Tuple => Node::Tuple(TupleNode(self.values)),
+            SeqKind::Struct(name) => Node::Struct(StructNode {
+                name,
+                fields: fields(self.values),
+            }),
+            SeqKind::Variant(name, variant) => Node::Enum(EnumNode {
+                name,
+                variant,
+                fields: fields(self.values),
+            }),
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeSeq {
+    type Ok = Node;
+    type Error = SerializeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Node, SerializeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeSeq {
+    type Ok = Node;
+    type Error = SerializeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Node, SerializeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeSeq {
+    type Ok = Node;
+    type Error = SerializeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Node, SerializeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeSeq {
+    type Ok = Node;
+    type Error = SerializeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Node, SerializeError> {
+        self.finish()
+    }
+}
+
+struct SerializeMap {
+    entries: Vec<(Node, Node)>,
+    /// The key of the entry whose value is next.
+    key: Option<Node>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Node;
+    type Error = SerializeError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.key = Some(key.serialize(TreeSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| SerializeError(String::from("map value without a key")))?;
+        self.entries.push((key, value.serialize(TreeSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Node, SerializeError> {
+        Ok(Node::Map(MapNode(self.entries)))
+    }
+}
+
+struct SerializeStruct {
+    name: &'static str,
+    /// Set for struct variants.
+    variant: Option<&'static str>,
+    names: Vec<NamedField<'static>>,
+    values: Vec<Node>,
+}
+
+impl SerializeStruct {
+    fn new(name: &'static str, variant: Option<&'static str>, len: usize) -> Self {
+        SerializeStruct {
+            name,
+            variant,
+            names: Vec::with_capacity(len),
+            values: Vec::with_capacity(len),
+        }
+    }
+
+    fn push<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerializeError> {
+        self.values.push(value.serialize(TreeSerializer)?);
+        self.names.push(NamedField::new(key));
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Node, SerializeError> {
+        let fields = FieldsNode {
+            names: Some(self.names),
+            values: self.values,
+        };
+        Ok(match self.variant {
+            None => Node::Struct(StructNode {
+                name: self.name,
+                fields,
+            }),
+            Some(variant) => Node::Enum(EnumNode {
+                name: self.name,
+                variant,
+                fields,
+            }),
+        })
+    }
+}
+
+impl ser::SerializeStruct for SerializeStruct {
+    type Ok = Node;
+    type Error = SerializeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Node, SerializeError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeStruct {
+    type Ok = Node;
+    type Error = SerializeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Self::Error> {
+        self.push(key, value)
+    }
+
+    fn end(self) -> Result<Node, SerializeError> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Serialize, Serializer};
+    use std::collections::BTreeMap;
+    use valuable::Valuable;
+
+    use super::SerdeAdapter;
+    use crate::custom_layer::testing::capture;
+    use crate::custom_layer::CustomJsonLayer;
+
+    /// Check that the layer writes a value the same through the adapter as `serde_json` does.
+    fn assert_written_like_serde_json<T: Serialize>(value: T) {
+        let expected = serde_json::to_value(&value).unwrap();
+        let adapter = SerdeAdapter::new(&value);
+        let captured = capture(CustomJsonLayer::default(), || {
+            tracing::info!(value = adapter.as_value());
+        });
+        assert_eq!(captured.json()[0]["fields"]["value"], expected);
+    }
+
+    #[derive(Serialize)]
+    struct Unit;
+
+    #[derive(Serialize)]
+    struct Newtype(u32);
+
+    #[derive(Serialize)]
+    struct Tuple(u32, &'static str);
+
+    #[derive(Serialize)]
+    struct Named {
+        name: &'static str,
+        aliases: Vec<&'static str>,
+        unit: Unit,
+    }
+
+    #[derive(Serialize)]
+    enum Spell {
+        Unit,
+        Newtype(u32),
+        Tuple(u32, &'static str),
+        Named {
+            mana: u32,
+            target: Option<&'static str>,
+        },
+    }
+
+    #[derive(Serialize)]
+    struct Flattened {
+        id: u32,
+        #[serde(flatten)]
+        named: Named,
+        #[serde(flatten)]
+        rest: BTreeMap<&'static str, u32>,
+    }
+
+    /// Serialized with `serialize_bytes`, like `serde_bytes` does.
+    struct Bytes(&'static [u8]);
+
+    impl Serialize for Bytes {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+
+    fn named() -> Named {
+        Named {
+            name: "Gandalf",
+            aliases: vec!["Mithrandir", "Olórin"],
+            unit: Unit,
+        }
+    }
+
+    #[test]
+    fn writes_structs_like_serde_json() {
+        assert_written_like_serde_json(Unit);
+        assert_written_like_serde_json(Newtype(7));
+        assert_written_like_serde_json(Tuple(7, "seven"));
+        assert_written_like_serde_json(named());
+    }
+
+    #[test]
+    fn writes_enums_like_serde_json() {
+        assert_written_like_serde_json(Spell::Unit);
+        assert_written_like_serde_json(Spell::Newtype(3));
+        assert_written_like_serde_json(Spell::Tuple(3, "fireball"));
+        assert_written_like_serde_json(Spell::Named {
+            mana: 3,
+            target: None,
+        });
+        assert_written_like_serde_json(vec![Spell::Unit, Spell::Newtype(1)]);
+    }
+
+    #[test]
+    fn writes_tuples_and_options_like_serde_json() {
+        assert_written_like_serde_json((1, "two", 3.5));
+        assert_written_like_serde_json(((), ((1,),)));
+        assert_written_like_serde_json(Some(1));
+        assert_written_like_serde_json(None::<u32>);
+        assert_written_like_serde_json(Some(Some(named())));
+    }
+
+    #[test]
+    fn writes_maps_with_non_string_keys_like_serde_json() {
+        assert_written_like_serde_json(BTreeMap::from([(1, "one"), (-2, "minus two")]));
+        assert_written_like_serde_json(BTreeMap::from([(u64::MAX, 1)]));
+        assert_written_like_serde_json(BTreeMap::from([('a', 1)]));
+
+        // The version of `serde_json` we're on refuses bool keys, but later ones write them as
+        // strings too.
+        let keys = SerdeAdapter::new(BTreeMap::from([(true, 1), (false, 0)]));
+        let captured = capture(CustomJsonLayer::default(), || {
+            tracing::info!(value = keys.as_value());
+        });
+        assert_eq!(
+            captured.json()[0]["fields"]["value"],
+            serde_json::json!({ "false": 0, "true": 1 })
+        );
+    }
+
+    #[test]
+    fn writes_flattened_fields_like_serde_json() {
+        assert_written_like_serde_json(Flattened {
+            id: 7,
+            named: named(),
+            rest: BTreeMap::from([("one", 1), ("two", 2)]),
+        });
+    }
+
+    #[test]
+    fn writes_bytes_like_serde_json() {
+        assert_written_like_serde_json(Bytes(b"tracing\xff"));
+        assert_written_like_serde_json(Bytes(b""));
+    }
+}